@@ -5,7 +5,8 @@
 //! There are around 30 students, a full fledged database would be an overkill (for next year?)
 //!
 //! Pending transactions are held in memory, these are cleared with every new block
-//! Only the last block is held in memory, every block is written to a file
+//! Every block is written to a file and the whole chain is loaded back into memory on startup
+//! Every block commits to the hash of the block before it, forming the chain
 //! Users are held in memory and they're also backed up to text files
 use chrono::{NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
@@ -13,22 +14,45 @@ use serde::{Deserialize, Serialize};
 pub type Fingerprint = String;
 pub type Id = String;
 
+/// Gradecoin amounts, balances, rewards and fees
+pub type Amount = u64;
+
 /// A block that was proposed with `transaction_list` and `nonce`
 /// that made `hash` valid, 6 zeroes at the left hand side of the hash (24 bytes)
 ///
-/// We are mining using blake2s algorithm, which produces 256 bit hashes.
-/// Hash/second is roughly 20x10^3.
+/// We are mining using blake2s algorithm by default, which produces 256 bit hashes.
+/// Hash/second is roughly 20x10^3. A network can choose another hash function with
+/// [`crate::config::Config::pow_hash`].
+///
+/// `previous_hash` is the `hash` of the block this one extends and `height` is its distance
+/// from the genesis block, a block is only accepted if it extends the current tip of the chain
 ///
-/// <https://serde.rs/container-attrs.html> might be valuable to normalize the
-/// serialize/deserialize conventions as these will be hashed
+/// `transactions` holds the bodies of the transactions in `transaction_list`, in the same order.
+/// `reward` and `traffic_reward` are the rewards the block paid out and `target` is the
+/// proof of work target it met, see [`crate::difficulty`]. The configuration of a network can
+/// change while it is running.
+/// These are filled in by Gradecoin when the block is accepted and are not a part of the hash
 ///
+/// The hash is taken over [`crate::canonical::naked_block`] of the [`NakedBlock`] of the block
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Block {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub transaction_list: Vec<Fingerprint>,
     pub nonce: u32,
     pub timestamp: NaiveDateTime,
+    #[serde(default)]
+    pub previous_hash: String,
+    #[serde(default)]
+    pub height: u64,
     pub hash: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reward: Option<Amount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic_reward: Option<Amount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
 }
 
 impl Default for Block {
@@ -37,12 +61,18 @@ impl Default for Block {
             transaction_list: vec!["gradecoin_bank".to_owned()],
             nonce: 0,
             timestamp: NaiveDate::from_ymd(2022, 4, 11).and_hms(20, 45, 00),
+            previous_hash: String::new(),
+            height: 0,
             hash: String::from("not_actually_mined"),
+            transactions: Vec::new(),
+            reward: None,
+            traffic_reward: None,
+            target: None,
         }
     }
 }
 
-/// For prototyping and letting serde handle everything json
+/// The part of a [`Block`] the proof of work is computed on, see [`crate::canonical`]
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[allow(clippy::module_name_repetitions)]
 pub struct NakedBlock {
@@ -50,6 +80,25 @@ pub struct NakedBlock {
     pub transaction_list: Vec<Fingerprint>,
     pub nonce: u32,
     pub timestamp: NaiveDateTime,
+    #[serde(default)]
+    pub previous_hash: String,
+    #[serde(default)]
+    pub height: u64,
+}
+
+/// Query parameters of a paginated block listing
+#[derive(Deserialize, Debug)]
+pub struct BlockPage {
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default = "BlockPage::default_per_page")]
+    pub per_page: usize,
+}
+
+impl BlockPage {
+    fn default_per_page() -> usize {
+        20
+    }
 }
 
 /// A transaction between `source` and `target` that moves `amount`
@@ -57,8 +106,55 @@ pub struct NakedBlock {
 pub struct Transaction {
     pub source: Fingerprint,
     pub target: Fingerprint,
-    pub amount: u16,
+    pub amount: Amount,
+    pub timestamp: NaiveDateTime,
+    /// Paid to the proposer of the block that mines it, only taken if the fee market is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Amount>,
+}
+
+/// A transaction that has paid its way into the system, as it is written to disk
+///
+/// Every proposal that paid the gas fee is recorded, even if it was rejected afterwards,
+/// so that the balances can be replayed from the records and the blocks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransactionRecord {
+    pub transaction: Transaction,
+    pub gas_fee: Amount,
+    /// When the transaction arrived, records written by older versions do not have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received: Option<NaiveDateTime>,
+    /// Set if the transaction left the pending pool without being mined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eviction: Option<Eviction>,
+    /// The fee held back from the source while the transaction is pending, given back if it is
+    /// evicted and paid to the proposer if it is mined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrowed_fee: Option<Amount>,
+}
+
+/// A pending transaction that was dropped without being mined
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Eviction {
     pub timestamp: NaiveDateTime,
+    pub reason: EvictionReason,
+    /// The part of the gas fee that was given back to the source
+    pub refund: Amount,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    /// Waited longer than the time to live of the pool
+    Expired,
+    /// Made room for a new transaction in a full pool
+    PoolFull,
+    /// Withdrawn by its source
+    Cancelled,
+    /// Replaced by a new transaction from its source
+    Replaced,
+    /// Removed by an admin
+    Removed,
 }
 
 /// A JWT Payload/Claims representation
@@ -68,11 +164,15 @@ pub struct Transaction {
 /// - `tha`: Transaction Hash, String (custom field)
 /// - `iat`: Issued At, Unix Time, epoch
 /// - `exp`: Expiration Time, epoch
+/// - `jti`: JWT ID, a unique identifier for the token, required if the network has
+///   [`crate::config::Config::jwt_replay_protection`] and always required for admin tokens
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Claims {
     pub tha: String,
     pub iat: usize,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
 }
 
 /// The plaintext of the initial user authentication request
@@ -90,3 +190,49 @@ pub struct InitialAuthRequest {
     pub iv: String,
     pub key: String,
 }
+
+/// An intervention of the network operators, the body of POST /admin
+///
+/// Tagged with `action`, for example `{"action":"set_balance","fingerprint":"...","balance":10}`.
+/// The `tha` claim of the admin JWT is the MD5 hash of this JSON, as it is serialized here.
+/// Admin tokens need a unique `jti` claim and cannot be valid for more than 5 minutes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminAction {
+    /// Overwrite the balance of a user
+    SetBalance {
+        fingerprint: Fingerprint,
+        balance: Amount,
+    },
+    /// Stop a user from proposing transactions and blocks
+    Freeze {
+        fingerprint: Fingerprint,
+    },
+    Unfreeze {
+        fingerprint: Fingerprint,
+    },
+    /// Drop a transaction from the pending transactions, its gas fee is not refunded
+    RemoveTransaction {
+        id: Id,
+    },
+    /// Read the `preapproved_users` file of the network again
+    ReloadPreapprovedUsers,
+    /// Write the `.guy` file of every user
+    Snapshot,
+    /// Read the configuration file of the network again and apply it
+    ReloadConfig,
+}
+
+/// A line of the audit log of a network, written for every authorized [`AdminAction`]
+///
+/// `previous_balance` is set by [`AdminAction::SetBalance`],
+/// the difference is taken into account when the balances are replayed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: NaiveDateTime,
+    pub action: AdminAction,
+    pub succeeded: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_balance: Option<Amount>,
+}
@@ -0,0 +1,168 @@
+//! # Canonical encoding
+//!
+//! The bytes that are hashed for a [`NakedBlock`] (the proof of work, see
+//! [`crate::difficulty::digest`]) and for a [`Transaction`] (the `tha` claim of its JWT is the
+//! MD5, its id is the SHA-256) are spelled out here instead of being left to serde, so that clients in any language can produce
+//! them and a change to the structs does not silently change the hashes.
+//!
+//! - A JSON object without any whitespace, the fields in this order:
+//!     - [`NakedBlock`]: `transaction_list`, `nonce`, `timestamp`, `previous_hash`, `height`
+//!     - [`Transaction`]: `source`, `target`, `amount`, `timestamp`, `fee`
+//! - Every field is present, `transaction_list` is written even if it is empty. The only
+//!   exception is the `fee` of a [`Transaction`], which is left out when it has none
+//! - Numbers are decimal integers without a sign or leading zeros
+//! - Strings are JSON strings, `"`, `\` and control characters are escaped and everything else,
+//!   non-ASCII characters included, is written as UTF-8
+//! - Timestamps are strings of the form `YYYY-MM-DDTHH:MM:SS`, without a time zone. A non-zero
+//!   fraction of a second follows as `.` and 3, 6 or 9 digits, the fewest that are exact:
+//!   `2021-04-13T20:55:30`, `2021-04-13T20:55:30.500`, `2021-04-13T20:55:30.000001`
+//!
+//! For example `{"source":"a","target":"b","amount":2,"timestamp":"2021-04-13T20:55:30"}`.
+//! This is also what `json.dumps(body, separators=(",", ":"), ensure_ascii=False)` gives in
+//! Python as long as the keys are in order, the default `ensure_ascii=True` escapes non-ASCII
+//! characters as `\uXXXX` instead.
+//!
+//! A transaction that replaces the pending transaction `id` (PUT /transaction/{id}) is signed over
+//! the id of the replaced transaction followed by its own text, see [`replacement`].
+//!
+//! `vectors/hashing.json` has examples with their hashes to check an implementation against.
+use crate::block::{NakedBlock, Transaction};
+use chrono::{NaiveDateTime, Timelike};
+
+/// The bytes of `block` that are hashed for the proof of work
+pub fn naked_block(block: &NakedBlock) -> Vec<u8> {
+    let transaction_list = block
+        .transaction_list
+        .iter()
+        .map(|id| string(id))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        r#"{{"transaction_list":[{}],"nonce":{},"timestamp":{},"previous_hash":{},"height":{}}}"#,
+        transaction_list,
+        block.nonce,
+        string(&timestamp(&block.timestamp)),
+        string(&block.previous_hash),
+        block.height
+    )
+    .into_bytes()
+}
+
+/// The text of `transaction` that is hashed for the `tha` claim
+pub fn transaction(transaction: &Transaction) -> String {
+    let fee = transaction
+        .fee
+        .map(|fee| format!(r#","fee":{fee}"#))
+        .unwrap_or_default();
+
+    format!(
+        r#"{{"source":{},"target":{},"amount":{},"timestamp":{}{}}}"#,
+        string(&transaction.source),
+        string(&transaction.target),
+        transaction.amount,
+        string(&timestamp(&transaction.timestamp)),
+        fee
+    )
+}
+
+/// The text that is hashed for the `tha` claim of a transaction that replaces the pending
+/// transaction `id`, the id followed by the [`transaction`] text without a separator
+pub fn replacement(id: &str, replaced_by: &Transaction) -> String {
+    format!("{id}{}", transaction(replaced_by))
+}
+
+/// `timestamp` as `YYYY-MM-DDTHH:MM:SS` with the fraction of a second if there is one
+pub fn timestamp(timestamp: &NaiveDateTime) -> String {
+    let seconds = timestamp.format("%Y-%m-%dT%H:%M:%S");
+    // Leap seconds are kept in the fraction by chrono
+    let nano = timestamp.nanosecond() % 1_000_000_000;
+
+    if nano == 0 {
+        seconds.to_string()
+    } else if nano.is_multiple_of(1_000_000) {
+        format!("{}.{:03}", seconds, nano / 1_000_000)
+    } else if nano.is_multiple_of(1_000) {
+        format!("{}.{:06}", seconds, nano / 1_000)
+    } else {
+        format!("{seconds}.{nano:09}")
+    }
+}
+
+/// `text` as a JSON string
+fn string(text: &str) -> String {
+    serde_json::to_string(text).expect("A string can always be written as JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PowHash;
+    use crate::difficulty;
+    use md5::Md5;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use std::fmt::Write;
+
+    /// `vectors/hashing.json` is published for clients, it has to agree with the code
+    fn vectors() -> Value {
+        serde_json::from_str(include_str!("../vectors/hashing.json")).unwrap()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+    }
+
+    #[test]
+    fn transaction_vectors() {
+        let vectors = vectors();
+        for vector in vectors["transactions"].as_array().unwrap() {
+            let body: Transaction = serde_json::from_value(vector["input"].clone()).unwrap();
+            let canonical = transaction(&body);
+
+            assert_eq!(canonical, vector["canonical"], "{}", vector["input"]);
+            assert_eq!(hex(&Md5::digest(canonical.as_bytes())), vector["tha"]);
+            assert_eq!(hex(&Sha256::digest(canonical.as_bytes())), vector["id"]);
+        }
+    }
+
+    #[test]
+    fn block_vectors() {
+        let vectors = vectors();
+        for vector in vectors["blocks"].as_array().unwrap() {
+            let block: NakedBlock = serde_json::from_value(vector["input"].clone()).unwrap();
+            let canonical = naked_block(&block);
+
+            assert_eq!(
+                String::from_utf8(canonical.clone()).unwrap(),
+                vector["canonical"],
+                "{}",
+                vector["input"]
+            );
+
+            let hashes = vector["hashes"].as_object().unwrap();
+            assert_eq!(hashes.len(), 5);
+            for (name, expected) in hashes {
+                let function: PowHash = serde_json::from_value(Value::from(name.as_str())).unwrap();
+                assert_eq!(
+                    hex(&difficulty::digest(function, &canonical)),
+                    *expected,
+                    "{name} of {}",
+                    vector["input"]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scrypt_parameters() {
+        let scrypt = &vectors()["scrypt"];
+        assert_eq!(scrypt["log_n"], difficulty::SCRYPT_LOG_N);
+        assert_eq!(scrypt["n"], 1_u32 << difficulty::SCRYPT_LOG_N);
+        assert_eq!(scrypt["r"], difficulty::SCRYPT_R);
+        assert_eq!(scrypt["p"], difficulty::SCRYPT_P);
+    }
+}
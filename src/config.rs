@@ -1,22 +1,92 @@
 //! # Configuration
 //!
 //! This module holds the data structures for network configuration.
-use crate::block::Fingerprint;
+use crate::block::{Amount, Fingerprint};
+use crate::db::read_private_key;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::{collections::HashMap, path::Path};
 
 /// Configuration struct for a single bot
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[allow(clippy::module_name_repetitions)]
 pub struct BotConfig {
     /// The initial balance of this bot.
-    pub starting_balance: u16,
+    pub starting_balance: Amount,
+}
+
+/// Difficulty retargeting of a network, see [`crate::difficulty`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RetargetConfig {
+    /// The target is adjusted after every `interval` blocks
+    pub interval: u64,
+
+    /// Desired time between two blocks, in seconds
+    pub block_time: u64,
+}
+
+/// Bounds on the timestamps of blocks and transactions, relative to the UTC clock of the server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ClockSkewConfig {
+    /// How many seconds a timestamp can be ahead of the server
+    pub max_future: u64,
+
+    /// How many seconds a timestamp can be behind the server
+    pub max_past: u64,
+}
+
+/// Limits of the pending transaction pool, evicted transactions are kept in their
+/// [`crate::block::TransactionRecord`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MempoolConfig {
+    /// Seconds a transaction can wait for a block, forever if not given
+    #[serde(default)]
+    pub ttl: Option<u64>,
+
+    /// Most transactions the pool can hold, the cheapest and then the oldest one is evicted to make
+    /// room for a new one that paid more. A new one that would be the cheapest is turned away.
+    /// Unbounded if not given
+    #[serde(default)]
+    pub max_size: Option<usize>,
+
+    /// Give the gas fee of an expired or evicted transaction back to its source
+    #[serde(default)]
+    pub refund_gas: bool,
+}
+
+/// Bounds of the fee a transaction pays to the proposer of the block that mines it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FeeMarketConfig {
+    /// Lowest fee a transaction can offer
+    pub min_fee: Amount,
+
+    /// Highest fee a transaction can offer
+    pub max_fee: Amount,
+}
+
+/// The hash function blocks are mined with, see [`crate::difficulty::digest`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PowHash {
+    #[default]
+    Blake2s,
+    Sha256,
+    /// SHA-256 of the SHA-256 digest, as in Bitcoin
+    DoubleSha256,
+    /// Blake2b with a 256 bit output
+    Blake2b,
+    /// Memory-hard, see [`crate::difficulty::SCRYPT_LOG_N`]
+    Scrypt,
 }
 
 /// Configuration for a single network
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Config {
+    /// The file this configuration was read from
+    #[serde(skip)]
+    pub path: String,
+
     /// Name of the network
     pub name: String,
 
@@ -37,29 +107,78 @@ pub struct Config {
     /// First line is ignored.
     pub preapproved_users: String,
 
+    /// Private key of this network in PKCS#1 PEM format, decrypts the registration requests
+    ///
+    /// The key given on the command line is used if not given.
+    /// Students need the matching public key to register.
+    #[serde(default, skip_serializing)]
+    pub private_key: Option<String>,
+
     /// Valid blocks should have this many transactions
     pub block_transaction_count: u8,
 
     /// How many zero hexadecimal characters should a correct hash start with?
+    ///
+    /// The starting difficulty if the network has [`Config::retarget`]
     pub hash_zeros: u8,
 
+    /// How many zero bits should a correct hash start with? Takes the place of
+    /// [`Config::hash_zeros`] if given, for difficulties between two hexadecimal characters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_zero_bits: Option<u16>,
+
+    /// The hash function of the proof of work, Blake2s if not given
+    #[serde(default)]
+    pub pow_hash: PowHash,
+
+    /// Adjust the difficulty to keep a steady block interval, the difficulty is fixed if not given
+    #[serde(default)]
+    pub retarget: Option<RetargetConfig>,
+
+    /// Reject blocks and transactions with timestamps too far from the clock of the server,
+    /// any timestamp is accepted if not given
+    #[serde(default)]
+    pub clock_skew: Option<ClockSkewConfig>,
+
+    /// Expiry and size limit of the pending transactions, they wait forever if not given
+    #[serde(default)]
+    pub mempool: Option<MempoolConfig>,
+
+    /// Let transactions pay a fee to the block proposer, transactions cannot carry a fee if not
+    /// given
+    #[serde(default)]
+    pub fee_market: Option<FeeMarketConfig>,
+
     /// Inital registration bonus
-    pub register_bonus: u16,
+    pub register_bonus: Amount,
 
     /// Coinbase reward
-    pub block_reward: u16,
+    pub block_reward: Amount,
 
     /// Transaction gas fee
-    pub tx_gas_fee: u16,
+    pub tx_gas_fee: Amount,
 
     /// Transaction amount upper limit
-    pub tx_upper_limit: u16,
+    pub tx_upper_limit: Amount,
 
     /// Transaction amount lower limit
-    pub tx_lower_limit: u16,
+    pub tx_lower_limit: Amount,
 
     /// Transaction traffic reward
-    pub tx_traffic_reward: u16,
+    pub tx_traffic_reward: Amount,
+
+    /// Reject JWTs that were used before
+    ///
+    /// Tokens need a unique `jti` claim, which is remembered until the token expires.
+    /// Needs [`Config::jwt_max_lifetime`] so that they are not remembered for too long
+    #[serde(default)]
+    pub jwt_replay_protection: bool,
+
+    /// Longest a JWT can be valid for in seconds, `exp - iat`
+    ///
+    /// Tokens issued in the future are rejected as well. No limit if not given
+    #[serde(default)]
+    pub jwt_max_lifetime: Option<u64>,
 
     /// The configuration of the bots in this network.
     /// Maps bot fingerprints to their configurations.
@@ -77,7 +196,7 @@ impl Config {
                 return None;
             }
         };
-        let config: Config = match serde_yaml::from_reader(file) {
+        let mut config: Config = match serde_yaml::from_reader(file) {
             Ok(c) => c,
             Err(e) => {
                 error!("Cannot parse config file: {}", filename);
@@ -87,6 +206,152 @@ impl Config {
         };
         // File closes automatically when it goes out of scope.
         info!("Config file read successfully: {}", filename);
+        filename.clone_into(&mut config.path);
         Some(config)
     }
+
+    /// Every problem with this configuration, all at once
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.name.is_empty() {
+            problems.push("name should not be empty".to_owned());
+        }
+        if self.url_prefix.contains('/') {
+            problems.push(format!(
+                "url_prefix should be a single path segment without '/', it is '{}'",
+                self.url_prefix
+            ));
+        }
+        if !Path::new(&self.preapproved_users).is_file() {
+            problems.push(format!(
+                "preapproved_users file '{}' does not exist",
+                self.preapproved_users
+            ));
+        }
+        if let Some(private_key) = &self.private_key {
+            if let Err(e) = read_private_key(Path::new(private_key)) {
+                problems.push(format!("private_key is not usable: {e}"));
+            }
+        }
+        if self.block_transaction_count == 0 {
+            problems.push(
+                "block_transaction_count should be at least 1, the first transaction of a block \
+                names its proposer"
+                    .to_owned(),
+            );
+        }
+        // Every proof of work hash is 64 hexadecimal characters
+        if self.hash_zeros > 64 {
+            problems.push(format!(
+                "hash_zeros should be at most 64, it is {}",
+                self.hash_zeros
+            ));
+        }
+        if let Some(bits) = self.hash_zero_bits.filter(|bits| *bits > 256) {
+            problems.push(format!(
+                "hash_zero_bits should be at most 256, it is {bits}"
+            ));
+        }
+        if self.tx_lower_limit > self.tx_upper_limit {
+            problems.push(format!(
+                "tx_lower_limit ({}) should not be greater than tx_upper_limit ({})",
+                self.tx_lower_limit, self.tx_upper_limit
+            ));
+        }
+        if self.tx_traffic_reward > self.tx_lower_limit {
+            problems.push(format!(
+                "tx_traffic_reward ({}) should not be greater than tx_lower_limit ({}), \
+                otherwise sending coins makes money",
+                self.tx_traffic_reward, self.tx_lower_limit
+            ));
+        }
+        if let Some(retarget) = &self.retarget {
+            if retarget.interval < 2 {
+                problems.push(format!(
+                    "retarget.interval should be at least 2 blocks, it is {}",
+                    retarget.interval
+                ));
+            }
+            if retarget.block_time == 0 {
+                problems.push("retarget.block_time should be at least 1 second".to_owned());
+            }
+        }
+        if let Some(mempool) = &self.mempool {
+            if mempool.ttl == Some(0) {
+                problems.push("mempool.ttl should be at least 1 second".to_owned());
+            }
+            if mempool.max_size == Some(0) {
+                problems.push("mempool.max_size should be at least 1".to_owned());
+            }
+        }
+        if let Some(fee_market) = &self.fee_market {
+            if fee_market.min_fee > fee_market.max_fee {
+                problems.push(format!(
+                    "fee_market.min_fee ({}) should not be more than fee_market.max_fee ({})",
+                    fee_market.min_fee, fee_market.max_fee
+                ));
+            }
+        }
+        if self.jwt_max_lifetime == Some(0) {
+            problems.push("jwt_max_lifetime should be at least 1 second".to_owned());
+        }
+        if self.jwt_replay_protection && self.jwt_max_lifetime.is_none() {
+            problems.push(
+                "jwt_replay_protection needs jwt_max_lifetime, every jti is remembered until its \
+                token expires"
+                    .to_owned(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Problems with running this network next to `others` in the same process
+    pub fn clashes_with(&self, others: &[Config]) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for other in others {
+            if other.name == self.name {
+                problems.push(format!(
+                    "name '{}' is already used by {}",
+                    self.name, other.path
+                ));
+            }
+            if other.url_prefix == self.url_prefix {
+                problems.push(format!(
+                    "url_prefix '{}' is already used by {}",
+                    self.url_prefix, other.path
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Every field that differs from `other`, as `field: old -> new`
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+
+        let mut fields: Vec<&String> = old.keys().chain(new.keys()).collect();
+        fields.sort();
+        fields.dedup();
+
+        fields
+            .into_iter()
+            .filter_map(|field| {
+                let old_value = old.get(field).unwrap_or(&Value::Null);
+                let new_value = new.get(field).unwrap_or(&Value::Null);
+                (old_value != new_value).then(|| format!("{field}: {old_value} -> {new_value}"))
+            })
+            .collect()
+    }
 }
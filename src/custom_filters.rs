@@ -1,5 +1,5 @@
 //! # Functions that extracts Structs to be used in warp routines
-use crate::block::{Block, InitialAuthRequest, Transaction};
+use crate::block::{AdminAction, Block, BlockPage, InitialAuthRequest, Transaction};
 use crate::Db;
 use std::convert::Infallible;
 use warp::{Filter, Rejection};
@@ -35,3 +35,16 @@ pub fn auth_header() -> impl Filter<Extract = (String,), Error = Rejection> + Cl
 pub fn block_json_body() -> impl Filter<Extract = (Block,), Error = Rejection> + Clone {
     warp::body::content_length_limit(1024 * 32).and(warp::body::json())
 }
+
+/// Extracts an `AdminAction` JSON body from the request
+/// Accepts only JSON encoded `AdminAction` body and rejects big payloads
+pub fn admin_action_json_body() -> impl Filter<Extract = (AdminAction,), Error = Rejection> + Clone
+{
+    warp::body::content_length_limit(1024 * 32).and(warp::body::json())
+}
+
+/// Extracts the `page` and `per_page` query parameters of a block listing
+/// Both are optional, see [`BlockPage`] for the defaults
+pub fn block_page_query() -> impl Filter<Extract = (BlockPage,), Error = Rejection> + Clone {
+    warp::query::<BlockPage>()
+}
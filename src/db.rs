@@ -1,67 +1,170 @@
 //! # Global Database representation
 //!
-//! [`Db::blockchain`] is just the last block that was mined.
-//! All the blocks are written to disk as text files whenever they are accepted.
+//! [`Db::blockchain`] is every block that was mined, the genesis block included.
+//! Every block points to its predecessor through [`Block::previous_hash`].
+//! All the blocks are written to disk as text files whenever they are accepted,
+//! the whole chain is loaded back into memory on startup.
 //!
 //! [`Db::pending_transactions`] is the in memory representation of the waiting transactions.
 //! Every user can have only one outstanding transaction at any given time.
+//! A pending transaction is written to disk when it arrives and removed when it leaves the pool,
+//! so that the pool survives a restart.
+//!
+//! [`Db::transaction_records`] holds every transaction that paid the gas fee, mined or not.
+//! They are also written to disk as text files.
 //!
 //! [`Db::users`] is the in memory representation of the users,
 //! with their public keys, `metu_ids` and gradecoin balances.
-use crate::block::{Block, Fingerprint, Id, Transaction};
+//! The `.guy` files of the users are snapshots, the balances can be rebuilt from the blocks and the
+//! transaction records, see [`crate::replay`].
+use crate::block::{AuditEntry, Block, Fingerprint, Id, Transaction, TransactionRecord};
 use crate::config::{BotConfig, Config};
 use crate::student::{MetuId, User, UserAtRest};
-use log::info;
+use log::{info, warn};
 use parking_lot::RwLock;
-use std::{collections::HashMap, fs, io, path::PathBuf, sync::Arc};
+use rsa::RSAPrivateKey;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct Db {
-    pub blockchain: Arc<RwLock<Block>>,
+    pub blockchain: Arc<RwLock<Chain>>,
     pub pending_transactions: Arc<RwLock<HashMap<Id, Transaction>>>,
+    pub transaction_records: Arc<RwLock<HashMap<Id, TransactionRecord>>>,
     pub users: Arc<RwLock<HashMap<Fingerprint, User>>>,
-    pub config: Config,
-    preapproved_users: Vec<MetuId>,
+    /// `jti` claims of the tokens that were used, with their expiration time
+    pub seen_tokens: Arc<RwLock<HashMap<(Fingerprint, String), usize>>>,
+    /// Swapped as a whole when the configuration is reloaded, see [`Db::reload_config`]
+    config: Arc<RwLock<Arc<Config>>>,
+    /// Decrypts the registration requests
+    pub private_key: Arc<RSAPrivateKey>,
+    /// Where the `blocks`, `users`, `transactions`, `pending` and `audit` folders are
+    data_dir: PathBuf,
+    /// Name of the network, kept outside of `config` so that the directory helpers never take
+    /// its lock, it cannot change without a restart anyway
+    name: String,
+    preapproved_users: Arc<RwLock<Vec<MetuId>>>,
 }
 
 impl Db {
-    pub fn new(config: Config) -> Self {
-        fs::create_dir_all(format!("blocks/{}", config.name)).unwrap();
-        fs::create_dir_all(format!("users/{}", config.name)).unwrap();
+    /// `default_key` is used if the network does not have its own [`Config::private_key`],
+    /// it has to be given for such a network
+    pub fn new(config: Config, data_dir: &Path, default_key: Option<Arc<RSAPrivateKey>>) -> Self {
+        let data_dir = data_dir.to_path_buf();
+        let network_dir = |folder: &str| data_dir.join(folder).join(&config.name);
+        fs::create_dir_all(network_dir("blocks")).unwrap();
+        fs::create_dir_all(network_dir("users")).unwrap();
+        fs::create_dir_all(network_dir("transactions")).unwrap();
+        fs::create_dir_all(network_dir("pending")).unwrap();
+        fs::create_dir_all(data_dir.join("audit")).unwrap();
 
         // Load bots
         let users: HashMap<Fingerprint, User> = get_bots(&config.bots);
 
+        // Registration requests of this network are encrypted with its own key, if it has one
+        let private_key = match &config.private_key {
+            Some(path) => {
+                info!("[{}] Using the private key at {}", config.name, path);
+                Arc::new(read_private_key(Path::new(path)).unwrap_or_else(|e| panic!("{}", e)))
+            }
+            None => default_key.expect("the private key of Gradecoin is loaded for this network"),
+        };
+
         // Load the list of users who can register
-        let preapproved_users = read_approved_users(&config.preapproved_users);
+        let preapproved_users =
+            read_approved_users(&config.preapproved_users).unwrap_or_else(|e| panic!("{}", e));
+
+        // Load every transaction that paid the gas fee
+        let transaction_records = read_transaction_records(&network_dir("transactions")).unwrap();
 
+        let name = config.name.clone();
         let mut db = Db {
-            blockchain: Arc::new(RwLock::new(Block::default())),
+            blockchain: Arc::new(RwLock::new(Chain::default())),
             pending_transactions: Arc::new(RwLock::new(HashMap::new())),
+            transaction_records: Arc::new(RwLock::new(transaction_records)),
             users: Arc::new(RwLock::new(users)),
-            config,
-            preapproved_users,
+            seen_tokens: Arc::new(RwLock::new(HashMap::new())),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            private_key,
+            data_dir,
+            name,
+            preapproved_users: Arc::new(RwLock::new(preapproved_users)),
         };
 
-        // Load the latest block, continue from where we left off
-        if let Some(block_path) = last_block_content(&db.config.name) {
-            db.populate_with_last_block(block_path);
+        // Load the chain, continue from where we left off
+        if let Ok(block_paths) = read_block_name(&db.blocks_dir()) {
+            db.populate_with_blocks(block_paths);
         }
 
         // Load the users that had registered themselves
-        if let Ok(users_path) = read_users(&db.config.name) {
+        if let Ok(users_path) = read_users(&db.users_dir()) {
             db.populate_with_users(users_path);
         }
 
+        // Load the transactions that were waiting for a block
+        db.populate_with_pending_transactions(read_transactions(&db.pending_dir()).unwrap());
+
         db
     }
 
-    fn populate_with_last_block(&mut self, path: String) {
-        info!("Populating db with the latest block {}", path);
-        let file = fs::read(path).unwrap();
-        let json = std::str::from_utf8(&file).unwrap();
-        let block: Block = serde_json::from_str(json).unwrap();
-        *self.blockchain.write() = block;
+    fn populate_with_pending_transactions(&mut self, mut pending: HashMap<Id, Transaction>) {
+        // A block could have been written just before the pending transactions were removed
+        let blockchain = self.blockchain.read();
+        for block in blockchain.range(0, blockchain.block_count()) {
+            for id in &block.transaction_list {
+                if pending.remove(id).is_some() {
+                    let _ = fs::remove_file(self.pending_dir().join(format!("{id}.tx")));
+                }
+            }
+        }
+
+        drop(blockchain);
+
+        info!(
+            "[{}] {} pending transactions are restored",
+            self.name,
+            pending.len()
+        );
+        *self.pending_transactions.write() = pending;
+    }
+
+    fn populate_with_blocks(&mut self, files: Vec<PathBuf>) {
+        let mut blocks: Vec<Block> = files
+            .into_iter()
+            .map(|path| {
+                info!("Populating db with block {:?}", path);
+                let file = fs::read(path).unwrap();
+                let json = std::str::from_utf8(&file).unwrap();
+                serde_json::from_str(json).unwrap()
+            })
+            .collect();
+
+        // Blocks written before heights existed have no previous_hash and a height of 0,
+        // they come first in the order they were mined and take their height from their position
+        blocks.sort_by_key(|block| {
+            (
+                !block.previous_hash.is_empty(),
+                block.height,
+                block.timestamp,
+            )
+        });
+
+        let mut chain = self.blockchain.write();
+        for mut block in blocks {
+            let height = u64::try_from(chain.block_count()).unwrap();
+            if block.previous_hash.is_empty() {
+                block.height = height;
+            }
+            chain
+                .push(block)
+                .unwrap_or_else(|e| panic!("[{}] The chain on disk is broken: {}", self.name, e));
+        }
     }
 
     fn populate_with_users(&mut self, files: Vec<PathBuf>) {
@@ -79,41 +182,120 @@ impl Db {
         }
     }
 
-    pub fn is_user_preapproved(&self, id: &Id, passwd: &String) -> bool {
-        for user in &self.preapproved_users {
-            if *user.get_id() == *id && *user.get_passwd() == *passwd {
-                return true;
+    /// The current configuration of the network
+    ///
+    /// Take it once and hold on to it, so that a reload does not change the rules halfway through
+    /// a request
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
+    /// Read the configuration file of the network again and apply it at once,
+    /// returns the fields that changed
+    ///
+    /// The name, the URL prefix and the private key of a network need a restart to change.
+    /// New bots join with their starting balance, removed bots stay until the next restart
+    pub fn reload_config(&self) -> Result<Vec<String>, String> {
+        let current = self.config();
+        let new = Config::read(&current.path)
+            .ok_or_else(|| format!("Cannot read {}, see the logs for details", current.path))?;
+        new.validate().map_err(|problems| problems.join(", "))?;
+
+        if new.name != current.name
+            || new.url_prefix != current.url_prefix
+            || new.private_key != current.private_key
+        {
+            return Err(
+                "The name, the URL prefix and the private key of a network cannot change \
+                without a restart"
+                    .to_owned(),
+            );
+        }
+
+        // Read everything before anything is applied
+        let preapproved_users = if new.preapproved_users == current.preapproved_users {
+            None
+        } else {
+            Some(read_approved_users(&new.preapproved_users)?)
+        };
+
+        let changes = current.diff(&new);
+        for change in &changes {
+            info!("[{}] Config change {}", new.name, change);
+        }
+
+        for fingerprint in current.bots.keys() {
+            if !new.bots.contains_key(fingerprint) {
+                warn!(
+                    "[{}] Bot {} is removed from the config, it stays until the next restart",
+                    new.name, fingerprint
+                );
             }
         }
 
-        false
+        // Never hold the users lock and the config lock together, the handlers take them in the
+        // other order
+        {
+            let mut users = self.users.write();
+            for (fingerprint, bot) in get_bots(&new.bots) {
+                users.entry(fingerprint).or_insert(bot);
+            }
+        }
+        if let Some(preapproved_users) = preapproved_users {
+            *self.preapproved_users.write() = preapproved_users;
+        }
+        *self.config.write() = Arc::new(new);
+
+        Ok(changes)
     }
-}
 
-fn last_block_content(config_name: &str) -> Option<String> {
-    let blocks = read_block_name(config_name).unwrap();
+    /// Accepted blocks of this network
+    pub fn blocks_dir(&self) -> PathBuf {
+        self.data_dir.join("blocks").join(&self.name)
+    }
+
+    /// `.guy` snapshots of the users of this network
+    pub fn users_dir(&self) -> PathBuf {
+        self.data_dir.join("users").join(&self.name)
+    }
+
+    /// Transaction records of this network
+    pub fn transactions_dir(&self) -> PathBuf {
+        self.data_dir.join("transactions").join(&self.name)
+    }
+
+    /// Transactions of this network that are waiting for a block
+    pub fn pending_dir(&self) -> PathBuf {
+        self.data_dir.join("pending").join(&self.name)
+    }
 
-    if blocks.is_empty() {
-        return None;
+    /// Audit log of this network, one [`AuditEntry`] per line
+    pub fn audit_file(&self) -> PathBuf {
+        self.data_dir
+            .join("audit")
+            .join(format!("{}.log", self.name))
     }
 
-    let last_block = blocks[0].to_str().unwrap();
-    let mut last_block = parse_block(last_block);
-    let mut last_block_index = 0;
+    /// Read the `preapproved_users` file again, returns the number of users who can register
+    pub fn reload_preapproved_users(&self) -> Result<usize, String> {
+        let preapproved_users = read_approved_users(&self.config().preapproved_users)?;
+        let count = preapproved_users.len();
+        *self.preapproved_users.write() = preapproved_users;
+        Ok(count)
+    }
 
-    for (index, block) in blocks.iter().enumerate() {
-        let block = block.to_str().unwrap();
-        let block = parse_block(block);
-        if block > last_block {
-            last_block = block;
-            last_block_index = index;
+    pub fn is_user_preapproved(&self, id: &Id, passwd: &String) -> bool {
+        for user in self.preapproved_users.read().iter() {
+            if *user.get_id() == *id && *user.get_passwd() == *passwd {
+                return true;
+            }
         }
+
+        false
     }
-    return Some(blocks[last_block_index].to_str().unwrap().parse().unwrap());
 }
 
-fn read_block_name(config_name: &str) -> io::Result<Vec<PathBuf>> {
-    let path = format!("./blocks/{}", config_name);
+fn read_block_name(path: &Path) -> io::Result<Vec<PathBuf>> {
     let entries = fs::read_dir(path)?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()?;
@@ -121,16 +303,44 @@ fn read_block_name(config_name: &str) -> io::Result<Vec<PathBuf>> {
     Ok(entries)
 }
 
-fn parse_block(path: &str) -> u64 {
-    let start_pos = path.rfind('/').unwrap() + 1;
-    let end_pos = path.find(".block").unwrap();
-    let block_str = path[start_pos..end_pos].to_string();
-    let block_u64: u64 = block_str.parse().unwrap();
-    block_u64
+/// The transactions in the `.tx` files of `path`, keyed by transaction id
+pub fn read_transactions(path: &Path) -> io::Result<HashMap<Id, Transaction>> {
+    read_tx_files(path)
+}
+
+/// Every transaction that paid the gas fee, keyed by transaction id
+pub fn read_transaction_records(path: &Path) -> io::Result<HashMap<Id, TransactionRecord>> {
+    read_tx_files(path)
+}
+
+/// The `.tx` files of `path`, keyed by their name
+///
+/// Files are not written atomically, a crash can leave one truncated.
+/// Such a file is skipped with a warning so that the network can still start
+fn read_tx_files<T: DeserializeOwned>(path: &Path) -> io::Result<HashMap<Id, T>> {
+    let mut contents = HashMap::new();
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let id = id.to_owned();
+        let parsed = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(content) => {
+                contents.insert(id, content);
+            }
+            Err(e) => warn!("Skipping the unreadable file {:?}: {}", path, e),
+        }
+    }
+
+    Ok(contents)
 }
 
-fn read_users(config_name: &str) -> io::Result<Vec<PathBuf>> {
-    let path = format!("./users/{}", config_name);
+fn read_users(path: &Path) -> io::Result<Vec<PathBuf>> {
     let entries = fs::read_dir(path)?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()?;
@@ -138,6 +348,84 @@ fn read_users(config_name: &str) -> io::Result<Vec<PathBuf>> {
     Ok(entries)
 }
 
+/// Every accepted block in order, starting with the genesis block
+///
+/// Blocks are held in a `Vec` where the index of a block is its height,
+/// `heights` maps block hashes to their index for lookups by hash
+#[derive(Debug)]
+pub struct Chain {
+    blocks: Vec<Block>,
+    heights: HashMap<String, usize>,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        let genesis = Block::default();
+        let mut heights = HashMap::new();
+        heights.insert(genesis.hash.clone(), 0);
+
+        Chain {
+            blocks: vec![genesis],
+            heights,
+        }
+    }
+}
+
+impl Chain {
+    /// The last accepted block
+    pub fn tip(&self) -> &Block {
+        // There is always at least the genesis block
+        self.blocks.last().unwrap()
+    }
+
+    /// Append a block that extends the current tip
+    ///
+    /// Fails without changing anything if the block does not point to the tip or its height is not
+    /// the next one, the index of a block has to stay its height. Blocks written before heights
+    /// existed have no `previous_hash` and are not linked
+    pub fn push(&mut self, block: Block) -> Result<(), String> {
+        let tip = self.tip();
+        if !block.previous_hash.is_empty() && block.previous_hash != tip.hash {
+            return Err(format!(
+                "Block {} extends {}, the tip is {}",
+                block.hash, block.previous_hash, tip.hash
+            ));
+        }
+
+        let height = self.blocks.len();
+        if usize::try_from(block.height).ok() != Some(height) {
+            return Err(format!(
+                "Block {} claims height {}, the next height is {}",
+                block.hash, block.height, height
+            ));
+        }
+
+        self.heights.insert(block.hash.clone(), height);
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    /// Number of blocks in the chain, the genesis block included
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn get_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.heights.get(hash).map(|&height| &self.blocks[height])
+    }
+
+    pub fn get_by_height(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height)
+    }
+
+    /// At most `count` blocks starting from `height`
+    pub fn range(&self, height: usize, count: usize) -> &[Block] {
+        let start = height.min(self.blocks.len());
+        let end = start.saturating_add(count).min(self.blocks.len());
+        &self.blocks[start..end]
+    }
+}
+
 /// Build bots from the given set of bot configurations.
 fn get_bots(bot_configs: &HashMap<Fingerprint, BotConfig>) -> HashMap<Fingerprint, User> {
     let mut index = 0;
@@ -153,27 +441,64 @@ fn get_bots(bot_configs: &HashMap<Fingerprint, BotConfig>) -> HashMap<Fingerprin
                     public_key: "not_used".to_owned(),
                     balance: config.starting_balance,
                     is_bot: true,
+                    is_frozen: false,
+                    register_bonus: None,
                 },
             )
         })
         .collect()
 }
 
-fn read_approved_users(filename: &str) -> Vec<MetuId> {
+fn read_approved_users(filename: &str) -> Result<Vec<MetuId>, String> {
     let mut approved_students: Vec<MetuId> = Vec::new();
-    let contents = fs::read_to_string(filename).unwrap_or_else(|_| {
-        panic!(
-            "{}",
-            format!(
-                "Expected {} in place to load preapproved students",
-                filename
-            )
-        )
-    });
+    let contents = fs::read_to_string(filename)
+        .map_err(|_| format!("Expected {filename} in place to load preapproved students"))?;
     let mut reader = csv::Reader::from_reader(contents.as_bytes());
     for student in reader.records() {
-        let student = student.unwrap();
+        let student = student.map_err(|e| format!("Malformed line in {filename}: {e}"))?;
+        if student.len() < 2 {
+            return Err(format!(
+                "Every line of {filename} should have a user ID and a password"
+            ));
+        }
         approved_students.push(MetuId::new(student[0].to_owned(), student[1].to_owned()));
     }
-    approved_students
+    Ok(approved_students)
+}
+
+/// Every entry of the audit log of a network, oldest first
+pub fn read_audit_log(path: &Path) -> io::Result<Vec<AuditEntry>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        // No admin actions yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    contents
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// Read an RSA private key in PKCS#1 PEM format
+pub fn read_private_key(path: &Path) -> Result<RSAPrivateKey, String> {
+    let name = path.display();
+    let pem = fs::read_to_string(path).map_err(|e| format!("error reading '{name}': {e}"))?;
+
+    // base64(der(pem))
+    // Our private key is saved in PEM (base64) format
+    let der_encoded =
+        pem.lines()
+            .filter(|line| !line.starts_with('-'))
+            .fold(String::new(), |mut data, line| {
+                data.push_str(line);
+                data
+            });
+    let der_bytes = base64::decode(&der_encoded)
+        .map_err(|e| format!("failed to decode base64 content of '{name}': {e}"))?;
+
+    RSAPrivateKey::from_pkcs1(&der_bytes).map_err(|e| format!("failed to parse key '{name}': {e}"))
 }
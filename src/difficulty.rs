@@ -0,0 +1,130 @@
+//! # Proof of work difficulty
+//!
+//! The hash of a block, read as a 256 bit big endian number, should not be greater than the
+//! target of its height. Starting with [`Config::hash_zeros`] zero hexadecimal characters is the
+//! same as being at most `2^(256 - 4 * hash_zeros) - 1`, [`Config::hash_zero_bits`] counts
+//! zero bits instead and is at most `2^(256 - hash_zero_bits) - 1`.
+//!
+//! With [`Config::retarget`], the target is adjusted after every `interval` blocks so that blocks
+//! are mined `block_time` seconds apart, judging by the timestamps of the last `interval` blocks.
+//! A single adjustment is at most [`MAX_ADJUSTMENT`] times in either direction.
+//!
+//! The hash of a block is [`digest`] of the JSON of its [`crate::block::NakedBlock`], with the
+//! [`Config::pow_hash`] of the network. Every choice gives 256 bits.
+//!
+//! Every accepted block stores the target it was mined under in [`Block::target`], so the target
+//! of the next block only depends on the tip of the chain and the configuration can change while
+//! the network is running. The zeros are only the starting point of a retargeting network.
+use crate::block::Block;
+use crate::config::{Config, PowHash};
+use crate::db::Chain;
+use blake2::{
+    digest::{Update, VariableOutput},
+    Blake2s, Digest, VarBlake2b,
+};
+use rsa::BigUint;
+use sha2::Sha256;
+use std::convert::TryFrom;
+
+/// Bound on a single adjustment, in both directions
+pub const MAX_ADJUSTMENT: u64 = 4;
+
+/// [`PowHash::Scrypt`] costs `2^SCRYPT_LOG_N` rounds, 1 MiB of memory with [`SCRYPT_R`]
+pub const SCRYPT_LOG_N: u8 = 10;
+/// Block size of [`PowHash::Scrypt`]
+pub const SCRYPT_R: u32 = 8;
+/// Parallelization of [`PowHash::Scrypt`], the salt is empty
+pub const SCRYPT_P: u32 = 1;
+
+/// The 256 bit proof of work hash of `data`
+pub fn digest(function: PowHash, data: &[u8]) -> Vec<u8> {
+    match function {
+        PowHash::Blake2s => Blake2s::digest(data).to_vec(),
+        PowHash::Sha256 => Sha256::digest(data).to_vec(),
+        PowHash::DoubleSha256 => Sha256::digest(&Sha256::digest(data)).to_vec(),
+        PowHash::Blake2b => {
+            let mut hasher = VarBlake2b::new(32).expect("32 bytes is a valid Blake2b output size");
+            hasher.update(data);
+            hasher.finalize_boxed().into_vec()
+        }
+        PowHash::Scrypt => {
+            let params = scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)
+                .expect("The scrypt parameters are valid");
+            let mut output = vec![0; 32];
+            scrypt::scrypt(data, &[], &params, &mut output)
+                .expect("32 bytes is a valid scrypt output size");
+            output
+        }
+    }
+}
+
+/// Target of a network that does not retarget
+pub fn initial_target(config: &Config) -> BigUint {
+    let zero_bits = usize::from(zero_bits(config).min(256));
+    (BigUint::from(1_u32) << (256 - zero_bits)) - BigUint::from(1_u32)
+}
+
+/// How many zero bits the hash of a block should start with when the network does not retarget
+pub fn zero_bits(config: &Config) -> u16 {
+    config
+        .hash_zero_bits
+        .unwrap_or_else(|| 4 * u16::from(config.hash_zeros))
+}
+
+/// The target the block after the tip of `chain` should meet
+pub fn next_target(chain: &Chain, config: &Config) -> BigUint {
+    let Some(retarget) = &config.retarget else {
+        return initial_target(config);
+    };
+
+    let tip = chain.tip();
+    let current = block_target(tip).unwrap_or_else(|| initial_target(config));
+
+    if tip.height == 0 || !tip.height.is_multiple_of(retarget.interval) {
+        return current;
+    }
+
+    // The window is the last `interval` blocks, the time between them is `interval - 1` gaps
+    let expected = (retarget.interval - 1) * retarget.block_time;
+    let first = usize::try_from(tip.height + 1 - retarget.interval)
+        .ok()
+        .and_then(|height| chain.get_by_height(height));
+    let Some(first) = first else {
+        return current;
+    };
+    if expected == 0 {
+        return current;
+    }
+
+    let elapsed = u64::try_from((tip.timestamp - first.timestamp).num_seconds())
+        .unwrap_or_default()
+        .clamp(expected / MAX_ADJUSTMENT, expected * MAX_ADJUSTMENT)
+        .max(1);
+
+    // Blocks that came too fast make the target smaller, mining harder
+    let target = current * elapsed / expected;
+    target.clamp(BigUint::from(1_u32), max_target())
+}
+
+/// The target `block` was mined under, blocks accepted before targets were stored have none
+pub fn block_target(block: &Block) -> Option<BigUint> {
+    block
+        .target
+        .as_ref()
+        .and_then(|target| BigUint::parse_bytes(target.as_bytes(), 16))
+}
+
+/// Does the `digest` of a block meet the `target`?
+pub fn meets_target(digest: &[u8], target: &BigUint) -> bool {
+    BigUint::from_bytes_be(digest) <= *target
+}
+
+/// `target` as 64 hexadecimal characters, the same shape as a block hash
+pub fn format_target(target: &BigUint) -> String {
+    format!("{:0>64}", target.to_str_radix(16))
+}
+
+/// Every hash meets this target
+fn max_target() -> BigUint {
+    (BigUint::from(1_u32) << 256) - BigUint::from(1_u32)
+}
@@ -1,30 +1,36 @@
 /// API handlers, the ends of each filter chain
-use crate::block::{AuthRequest, Block, Claims, InitialAuthRequest, NakedBlock, Transaction};
+use crate::block::{
+    AdminAction, Amount, AuditEntry, AuthRequest, Block, BlockPage, Claims, Eviction,
+    EvictionReason, Fingerprint, Id, InitialAuthRequest, NakedBlock, Transaction,
+    TransactionRecord,
+};
+use crate::canonical;
+use crate::config::{ClockSkewConfig, Config, RetargetConfig};
+use crate::difficulty;
 use crate::student::{MetuId, User, UserAtRest};
 use crate::Db;
 use aes::Aes128;
 use askama::Template;
-use blake2::{Blake2s, Digest};
 use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{decode, Algorithm, DecodingKey, TokenData, Validation};
-use lazy_static::lazy_static;
 use log::{debug, warn};
 use md5::Md5;
 use parking_lot::RwLockUpgradableReadGuard;
-use rsa::{PaddingScheme, RSAPrivateKey};
+use rsa::{PaddingScheme, PublicKeyParts};
 use serde::Serialize;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, HashSet},
-    convert::Infallible,
+    convert::{Infallible, TryFrom},
+    error::Error,
+    fmt::Write as _,
     fs,
     hash::Hash,
+    io::Write,
 };
-use warp::{http::StatusCode, reply};
-
-use crate::PRIVATE_KEY;
+use warp::{body::BodyDeserializeError, http::StatusCode, reject, reply, Rejection};
 
 // Encryption primitive
 type Aes128Cbc = Cbc<Aes128, Pkcs7>;
@@ -32,7 +38,12 @@ type Aes128Cbc = Cbc<Aes128, Pkcs7>;
 #[derive(Serialize, Debug)]
 struct UserFeedback {
     res: ResponseType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
     message: String,
+    /// The id of an accepted transaction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Id>,
 }
 
 #[derive(Debug, Serialize)]
@@ -41,21 +52,320 @@ enum ResponseType {
     Error,
 }
 
+/// Why a request was rejected, sent along with the message of the [`UserFeedback`]
+///
+/// Serialized as `SCREAMING_SNAKE_CASE` so that clients can branch on it,
+/// the HTTP status of the response is derived from it, see [`ErrorCode::status`]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    // Registration
+    InvalidBase64,
+    DecryptionFailed,
+    InvalidKeyOrIv,
+    InvalidUtf8,
+    MalformedAuthRequest,
+    NotPreapproved,
+    AlreadyRegistered,
+    InvalidPublicKey,
+
+    // JWT
+    InvalidToken,
+    InvalidRsaKey,
+    JwtExpired,
+    JwtError,
+    JwtHashMismatch,
+    JwtMissingJti,
+    JwtReused,
+    JwtLifetimeTooLong,
+    JwtIssuedInFuture,
+
+    // Blocks
+    NotEnoughTransactions,
+    WrongPreviousHash,
+    WrongHeight,
+    UnknownTransaction,
+    DuplicateTransaction,
+    HashMismatch,
+    InsufficientWork,
+    DoubleSpend,
+    BalanceOverflow,
+
+    // Transactions
+    UnknownUser,
+    UnknownTarget,
+    BotTransaction,
+    InsufficientGas,
+    InsufficientBalance,
+    DuplicatePendingTx,
+    SelfTransaction,
+    AmountOutOfBounds,
+    NotTransactionSource,
+    DuplicateTransactionId,
+    FeeOutOfBounds,
+    ReplacementFeeTooLow,
+    MempoolFull,
+
+    // Timestamps
+    TimestampOutOfBounds,
+    TimestampNotIncreasing,
+
+    // Lookups
+    BlockNotFound,
+    UserNotFound,
+    TransactionNotFound,
+
+    // Admin
+    UserFrozen,
+    ReloadFailed,
+
+    // Malformed requests
+    MalformedBody,
+    MissingHeader,
+    InvalidHeader,
+    InvalidQuery,
+    PayloadTooLarge,
+    LengthRequired,
+    UnsupportedMediaType,
+    MethodNotAllowed,
+}
+
+impl ErrorCode {
+    /// HTTP status of a response rejected with this code
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BlockNotFound | ErrorCode::UserNotFound | ErrorCode::TransactionNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::InvalidToken
+            | ErrorCode::InvalidRsaKey
+            | ErrorCode::JwtExpired
+            | ErrorCode::JwtError
+            | ErrorCode::JwtHashMismatch
+            | ErrorCode::JwtMissingJti
+            | ErrorCode::JwtReused
+            | ErrorCode::JwtLifetimeTooLong
+            | ErrorCode::JwtIssuedInFuture => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotPreapproved
+            | ErrorCode::BotTransaction
+            | ErrorCode::UserFrozen
+            | ErrorCode::NotTransactionSource => StatusCode::FORBIDDEN,
+            ErrorCode::AlreadyRegistered
+            | ErrorCode::WrongPreviousHash
+            | ErrorCode::WrongHeight
+            | ErrorCode::DuplicatePendingTx
+            | ErrorCode::DuplicateTransactionId => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::ReloadFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::MempoolFull => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// A rejection, the status is derived from `code`
+fn error_reply(code: ErrorCode, message: String) -> reply::WithStatus<reply::Json> {
+    reply::with_status(
+        reply::json(&UserFeedback {
+            res: ResponseType::Error,
+            code: Some(code),
+            message,
+            id: None,
+        }),
+        code.status(),
+    )
+}
+
+fn success_reply(message: String, status: StatusCode) -> reply::WithStatus<reply::Json> {
+    reply::with_status(
+        reply::json(&UserFeedback {
+            res: ResponseType::Success,
+            code: None,
+            message,
+            id: None,
+        }),
+        status,
+    )
+}
+
+/// Turns the rejections of warp's own filters into [`UserFeedback`]
+///
+/// Recovers the combined routes of every network and the static site, so that a request the static
+/// site can answer is never turned away by a network route with the same path.
+/// Not found rejections are passed on untouched for warp to answer.
+pub async fn handle_rejection(err: Rejection) -> Result<reply::WithStatus<reply::Json>, Rejection> {
+    if err.is_not_found() {
+        return Err(err);
+    }
+
+    let (code, message) = if let Some(e) = err.find::<BodyDeserializeError>() {
+        // The cause is the serde error, it names the missing or mistyped field
+        let cause = e
+            .source()
+            .map_or_else(|| e.to_string(), ToString::to_string);
+        (
+            ErrorCode::MalformedBody,
+            format!("The request body could not be parsed: {cause}"),
+        )
+    } else if let Some(e) = err.find::<reject::MissingHeader>() {
+        (
+            ErrorCode::MissingHeader,
+            format!(
+                "The request should have a '{}' header, for example '{}: {}<JWT>'",
+                e.name(),
+                e.name(),
+                BEARER
+            ),
+        )
+    } else if let Some(e) = err.find::<reject::InvalidHeader>() {
+        (
+            ErrorCode::InvalidHeader,
+            format!("The '{}' header of the request is not valid text", e.name()),
+        )
+    } else if err.find::<reject::InvalidQuery>().is_some() {
+        (
+            ErrorCode::InvalidQuery,
+            "The query string could not be parsed, \
+            `page` and `per_page` should be non-negative integers"
+                .to_owned(),
+        )
+    } else if err.find::<reject::PayloadTooLarge>().is_some() {
+        (
+            ErrorCode::PayloadTooLarge,
+            "The request body is larger than the 32 KiB limit".to_owned(),
+        )
+    } else if err.find::<reject::LengthRequired>().is_some() {
+        (
+            ErrorCode::LengthRequired,
+            "The request should have a 'Content-Length' header".to_owned(),
+        )
+    } else if err.find::<reject::UnsupportedMediaType>().is_some() {
+        (
+            ErrorCode::UnsupportedMediaType,
+            "The request body should be JSON with 'Content-Type: application/json'".to_owned(),
+        )
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        (
+            ErrorCode::MethodNotAllowed,
+            "This endpoint does not support this HTTP method".to_owned(),
+        )
+    } else {
+        return Err(err);
+    };
+
+    debug!("Request rejected with {:?}: {}", code, message);
+    Ok(error_reply(code, message))
+}
+
 const BEARER: &str = "Bearer ";
 
-lazy_static! {
-    static ref DER_ENCODED: String = PRIVATE_KEY
-        .lines()
-        .filter(|line| !line.starts_with('-'))
-        .fold(String::new(), |mut data, line| {
-            data.push_str(line);
-            data
-        });
+/// How far in the future `iat` can be, in seconds, to tolerate clock differences
+const IAT_LEEWAY: usize = 60;
+
+/// Stands in for the fingerprint of the admins when the `jti` of their tokens are remembered
+const ADMIN: &str = "admin";
 
-    // base64(der(pem))
-    // Our private key is saved in PEM (base64) format
-    static ref DER_BYTES: Vec<u8> = base64::decode(&*DER_ENCODED).expect("failed to decode base64 content");
-    static ref GRADECOIN_PRIVATE_KEY: RSAPrivateKey = RSAPrivateKey::from_pkcs1(&DER_BYTES).expect("failed to parse key");
+/// Longest an admin token can be valid for (exp - iat), in seconds, whatever the network allows
+/// for students
+const ADMIN_MAX_LIFETIME: u64 = 300;
+
+/// Upper bound on the number of blocks returned by a single GET /blocks request
+const MAX_BLOCKS_PER_PAGE: usize = 100;
+
+/// A single page of the chain, see [`list_chain`]
+#[derive(Serialize, Debug)]
+struct ChainPage<'a> {
+    total: usize,
+    page: usize,
+    per_page: usize,
+    blocks: &'a [Block],
+}
+
+/// A transaction and where it is, see [`transaction_status`]
+#[derive(Serialize, Debug)]
+struct TransactionState {
+    id: Id,
+    transaction: Transaction,
+    /// Blocks mined before the gas fees were recorded have transactions without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gas_fee: Option<Amount>,
+    #[serde(flatten)]
+    status: TransactionStatus,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum TransactionStatus {
+    /// Waiting for a block
+    Pending,
+    /// Mined in the block at `height`
+    Confirmed { height: u64, block_hash: String },
+    /// Left the pool without being mined
+    Evicted { eviction: Eviction },
+    /// Paid the gas fee but did not pass the checks after it
+    Rejected,
+}
+
+/// Everything that changed the balance of a user, see [`user_history`]
+#[derive(Serialize, Debug, Default)]
+struct UserHistory {
+    fingerprint: String,
+    balance: Amount,
+    confirmed: Vec<ConfirmedTransaction>,
+    pending: Vec<PendingTransaction>,
+    block_rewards: Vec<BlockReward>,
+    traffic_rewards: Vec<TrafficReward>,
+    gas_fees: Vec<GasFee>,
+    /// Fees paid to block proposers, including the ones held back for pending transactions
+    transaction_fees: Vec<GasFee>,
+    /// Transactions that were dropped from the pool, with the gas fees that were given back
+    evicted: Vec<EvictedTransaction>,
+}
+
+#[derive(Serialize, Debug)]
+struct ConfirmedTransaction {
+    id: String,
+    height: u64,
+    block_hash: String,
+    transaction: Transaction,
+}
+
+#[derive(Serialize, Debug)]
+struct EvictedTransaction {
+    id: String,
+    transaction: Transaction,
+    eviction: Eviction,
+}
+
+#[derive(Serialize, Debug)]
+struct PendingTransaction {
+    id: String,
+    transaction: Transaction,
+}
+
+#[derive(Serialize, Debug)]
+struct BlockReward {
+    height: u64,
+    block_hash: String,
+    amount: Amount,
+    /// The fees of the transactions in the block
+    fees: Amount,
+}
+
+#[derive(Serialize, Debug)]
+struct TrafficReward {
+    id: String,
+    height: u64,
+    amount: Amount,
+}
+
+#[derive(Serialize, Debug)]
+struct GasFee {
+    id: String,
+    amount: Amount,
 }
 
 /// POST request to /register endpoint
@@ -98,7 +408,8 @@ pub async fn authenticate_user(
     request: InitialAuthRequest,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    debug!("[{}] New user registration attempt", db.config.name);
+    let config = db.config();
+    debug!("[{}] New user registration attempt", config.name);
 
     // In essence PEM files are just base64 encoded versions of the DER encoded data.
     // ~tls.mbed.org
@@ -114,20 +425,18 @@ pub async fn authenticate_user(
                 &request.key, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
+            return Ok(error_reply(
+                ErrorCode::InvalidBase64,
+                format!(
                     "\"key\" field of initial auth request was not base64 encoded: {}, {}",
                     &request.key, err
                 ),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            ));
         }
     };
 
     // Decrypt the "key" field using Gradecoin's private key
-    let temp_key = match GRADECOIN_PRIVATE_KEY.decrypt(padding, &key_ciphertext) {
+    let temp_key = match db.private_key.decrypt(padding, &key_ciphertext) {
         Ok(k) => k,
         Err(err) => {
             debug!(
@@ -135,13 +444,10 @@ pub async fn authenticate_user(
                 err, &key_ciphertext
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "Failed to decrypt the 'key_ciphertext' field of the auth request"
-                    .to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            return Ok(error_reply(
+                ErrorCode::DecryptionFailed,
+                "Failed to decrypt the 'key_ciphertext' field of the auth request".to_owned(),
+            ));
         }
     };
 
@@ -154,15 +460,13 @@ pub async fn authenticate_user(
                 &request.iv, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
+            return Ok(error_reply(
+                ErrorCode::InvalidBase64,
+                format!(
                     "\"iv\" field of initial auth request was not base64 encoded: {}, {}",
                     &request.iv, err
                 ),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            ));
         }
     };
 
@@ -175,15 +479,13 @@ pub async fn authenticate_user(
                 &temp_key, &request.iv, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
+            return Ok(error_reply(
+                ErrorCode::InvalidKeyOrIv,
+                format!(
                     "Could not create a cipher from given 'temp_key': {:?} and 'IV': {}, {}",
                     &temp_key, &request.iv, err
                 ),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            ));
         }
     };
 
@@ -196,15 +498,13 @@ pub async fn authenticate_user(
                 &request.c, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
+            return Ok(error_reply(
+                ErrorCode::InvalidBase64,
+                format!(
                     "\"c\" field of initial auth request was not base64 encoded: {}, {}",
                     &request.c, err
                 ),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            ));
         }
     };
 
@@ -219,13 +519,11 @@ pub async fn authenticate_user(
                 &buf, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "Failed to decrypt the 'c' field of the auth request, 'iv' and 'k_temp' were valid so far though"
+            return Ok(error_reply(
+                ErrorCode::DecryptionFailed,
+                "Failed to decrypt the 'c' field of the auth request, 'iv' and 'k_temp' were valid so far though"
                     .to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            ));
         }
     };
 
@@ -238,13 +536,10 @@ pub async fn authenticate_user(
                 &auth_plaintext, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "P_AR couldn't get converted to UTF-8, please check your encoding"
-                    .to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            return Ok(error_reply(
+                ErrorCode::InvalidUtf8,
+                "P_AR couldn't get converted to UTF-8, please check your encoding".to_owned(),
+            ));
         }
     };
 
@@ -257,12 +552,10 @@ pub async fn authenticate_user(
                 &utf8_auth_plaintext, err
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "The P_AR JSON did not serialize correctly, did it include all 3 fields 'student_id', 'passwd' and 'public_key'?".to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            return Ok(error_reply(
+                ErrorCode::MalformedAuthRequest,
+                "The P_AR JSON did not serialize correctly, did it include all 3 fields 'student_id', 'passwd' and 'public_key'?".to_owned(),
+            ));
         }
     };
 
@@ -274,14 +567,11 @@ pub async fn authenticate_user(
             "Someone tried to auth with invalid credentials: {} {}",
             &request.student_id, &request.passwd
         );
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message:
-                "The credentials given ('student_id', 'passwd') cannot hold a Gradecoin account"
-                    .to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::NotPreapproved,
+            "The credentials given ('student_id', 'passwd') cannot hold a Gradecoin account"
+                .to_owned(),
+        ));
     };
 
     // Students should be able to authenticate once
@@ -291,25 +581,21 @@ pub async fn authenticate_user(
         for (_, user) in userlist.iter() {
             if user.user_id == privileged_student_id {
                 debug!("{} attempted to authenticate again", user.user_id);
-                let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message:
+                return Ok(error_reply(
+                    ErrorCode::AlreadyRegistered,
                     "This user is already authenticated, do you think this is a mistake? Contact me"
                         .to_owned(),
-            });
-                return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+                ));
             }
         }
     }
 
     // We're using this as the validator instead of anything reasonable
     if DecodingKey::from_rsa_pem(request.public_key.as_bytes()).is_err() {
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: "The RSA 'public_key' in 'P_AR' is not in valid PEM format".to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::InvalidPublicKey,
+            "The RSA 'public_key' in 'P_AR' is not in valid PEM format".to_owned(),
+        ));
     }
 
     let fingerprint = format!("{:x}", Sha256::digest(request.public_key.as_bytes()));
@@ -317,44 +603,70 @@ pub async fn authenticate_user(
     let new_user = User {
         user_id: privileged_student_id,
         public_key: request.public_key,
-        balance: db.config.register_bonus,
+        balance: config.register_bonus,
         is_bot: false,
+        is_frozen: false,
+        register_bonus: Some(config.register_bonus),
     };
 
     warn!("A new user has authenticated: {}", &new_user.user_id);
 
     // save the user to disk
-    let user_at_rest_json = serde_json::to_string(&UserAtRest {
-        fingerprint: fingerprint.clone(),
-        user: User {
-            user_id: new_user.user_id.clone(),
-            public_key: new_user.public_key.clone(),
-            balance: new_user.balance,
-            is_bot: false,
-        },
-    })
-    .unwrap();
-
-    write_guy_file(&db.config.name, &new_user.user_id, &user_at_rest_json);
+    write_user(&db, &fingerprint, &new_user);
 
     let mut userlist = db.users.write();
     userlist.insert(fingerprint.clone(), new_user);
 
-    let res_json = warp::reply::json(&UserFeedback {
-        res: ResponseType::Success,
-        message: format!(
+    Ok(success_reply(
+        format!(
             "You have authenticated to use Gradecoin with identifier {}",
             fingerprint
         ),
-    });
-
-    Ok(warp::reply::with_status(res_json, StatusCode::CREATED))
+        StatusCode::CREATED,
+    ))
 }
 
 /// GET /config
 /// Returns the configuration settings of this network in JSON.
 pub async fn get_config(db: Db) -> Result<impl warp::Reply, Infallible> {
-    Ok(reply::with_status(reply::json(&db.config), StatusCode::OK))
+    Ok(reply::with_status(
+        reply::json(&*db.config()),
+        StatusCode::OK,
+    ))
+}
+
+/// The proof of work target of the next block, see GET /difficulty
+#[derive(Serialize, Debug)]
+struct Difficulty {
+    height: u64,
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retarget: Option<RetargetConfig>,
+    /// Height of the first block mined under the next adjustment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_retarget_height: Option<u64>,
+}
+
+/// GET /difficulty
+/// Returns the target the next block should meet
+pub async fn get_difficulty(db: Db) -> Result<impl warp::Reply, Infallible> {
+    let config = db.config();
+    let blockchain = db.blockchain.read();
+    let height = blockchain.tip().height + 1;
+
+    let next_retarget_height = config
+        .retarget
+        .as_ref()
+        .map(|retarget| ((height - 1) / retarget.interval + 1) * retarget.interval + 1);
+
+    let difficulty = Difficulty {
+        height,
+        target: difficulty::format_target(&difficulty::next_target(&blockchain, &config)),
+        retarget: config.retarget.clone(),
+        next_retarget_height,
+    };
+
+    Ok(reply::with_status(reply::json(&difficulty), StatusCode::OK))
 }
 
 /// GET /version
@@ -373,6 +685,8 @@ pub async fn get_version() -> Result<impl warp::Reply, Infallible> {
 /// GET /transaction
 /// Returns JSON array of transactions
 pub async fn list_transactions(db: Db) -> Result<impl warp::Reply, Infallible> {
+    expire_pending(&db, &db.config());
+
     let mut result = HashMap::new();
 
     let transactions = db.pending_transactions.read();
@@ -384,6 +698,74 @@ pub async fn list_transactions(db: Db) -> Result<impl warp::Reply, Infallible> {
     Ok(reply::with_status(reply::json(&result), StatusCode::OK))
 }
 
+/// GET /transaction/{id}
+/// Returns where the transaction is, see [`TransactionStatus`]
+pub async fn transaction_status(id: Id, db: Db) -> Result<impl warp::Reply, Infallible> {
+    expire_pending(&db, &db.config());
+
+    let record = db.transaction_records.read().get(&id).cloned();
+    let gas_fee = record.as_ref().map(|record| record.gas_fee);
+
+    let pending = db.pending_transactions.read().get(&id).cloned();
+    if let Some(transaction) = pending {
+        return Ok(transaction_status_reply(&TransactionState {
+            id,
+            transaction,
+            gas_fee,
+            status: TransactionStatus::Pending,
+        }));
+    }
+
+    {
+        let blockchain = db.blockchain.read();
+        for block in blockchain.range(1, blockchain.block_count()) {
+            let Some(index) = block.transaction_list.iter().position(|mined| *mined == id) else {
+                continue;
+            };
+            // Blocks that were accepted before they carried their transactions fall back to
+            // the records
+            let transaction = block
+                .transactions
+                .get(index)
+                .cloned()
+                .or_else(|| record.as_ref().map(|record| record.transaction.clone()));
+            if let Some(transaction) = transaction {
+                return Ok(transaction_status_reply(&TransactionState {
+                    id,
+                    transaction,
+                    gas_fee,
+                    status: TransactionStatus::Confirmed {
+                        height: block.height,
+                        block_hash: block.hash.clone(),
+                    },
+                }));
+            }
+        }
+    }
+
+    let Some(record) = record else {
+        return Ok(error_reply(
+            ErrorCode::TransactionNotFound,
+            format!("Transaction {id} is not found in the system"),
+        ));
+    };
+
+    let status = match record.eviction {
+        Some(eviction) => TransactionStatus::Evicted { eviction },
+        None => TransactionStatus::Rejected,
+    };
+    Ok(transaction_status_reply(&TransactionState {
+        id,
+        transaction: record.transaction,
+        gas_fee,
+        status,
+    }))
+}
+
+fn transaction_status_reply(state: &TransactionState) -> reply::WithStatus<reply::Json> {
+    reply::with_status(reply::json(state), StatusCode::OK)
+}
+
 /// POST /block
 ///
 /// Proposes a new block for the next round.
@@ -393,36 +775,101 @@ pub async fn list_transactions(db: Db) -> Result<impl warp::Reply, Infallible> {
 /// This is the analogue of `coinbase` in Bitcoin works
 ///
 /// The `coinbase` transaction also gets something for their efforts.
+///
+/// The block has to extend the current tip, its `previous_hash` should be the hash of the last
+/// accepted block and its `height` one more than the height of the last accepted block.
+///
+/// The transactions are played out in order against the balances at the time of the proposal,
+/// a block that makes any source spend more than it has is rejected, see [`play_out_block`].
+///
+/// The accepted block is stored with the bodies of its transactions.
 #[allow(clippy::too_many_lines)] // temporary, should be refactored
 pub async fn propose_block(
-    new_block: Block,
+    mut new_block: Block,
     token: String,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    warn!("[{}] New block proposal: {:?}", db.config.name, &new_block);
+    // The whole proposal is judged by the configuration at the time it arrived
+    let config = db.config();
+    warn!("[{}] New block proposal: {:?}", config.name, &new_block);
+
+    expire_pending(&db, &config);
 
     // Check if there are enough transactions in the block
-    let block_transaction_count = db.config.block_transaction_count;
+    let block_transaction_count = config.block_transaction_count;
     if new_block.transaction_list.len() < block_transaction_count as usize {
         debug!(
             "{} transactions offered, needed {}",
             new_block.transaction_list.len(),
             block_transaction_count
         );
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: format!(
+        return Ok(error_reply(
+            ErrorCode::NotEnoughTransactions,
+            format!(
                 "There should be at least {} transactions in the block",
                 block_transaction_count
             ),
-        });
+        ));
+    }
 
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+    if let Some(clock_skew) = &config.clock_skew {
+        if let Err(message) = check_clock_skew(&new_block.timestamp, clock_skew) {
+            debug!("Block timestamp {} rejected", new_block.timestamp);
+            return Ok(error_reply(ErrorCode::TimestampOutOfBounds, message));
+        }
     }
 
     // proposer (first transaction fingerprint) checks
     let pending_transactions = db.pending_transactions.upgradable_read();
 
+    // Does the block extend the current tip of the chain?
+    // Checked while holding the pending transactions lock, which is only released after the block
+    // is pushed onto the chain, so that two competing proposals cannot both extend the same tip
+    {
+        let blockchain = db.blockchain.read();
+        let tip = blockchain.tip();
+
+        if new_block.previous_hash != tip.hash {
+            debug!(
+                "Block extends {}, current tip is {}",
+                new_block.previous_hash, tip.hash
+            );
+            return Ok(error_reply(
+                ErrorCode::WrongPreviousHash,
+                format!(
+                    "The previous_hash of the block should be the hash of the last block: {}",
+                    tip.hash
+                ),
+            ));
+        }
+
+        if new_block.timestamp <= tip.timestamp {
+            debug!(
+                "Block is dated {}, the last block {}",
+                new_block.timestamp, tip.timestamp
+            );
+            return Ok(error_reply(
+                ErrorCode::TimestampNotIncreasing,
+                format!(
+                    "The timestamp of the block should be later than the last block: {}",
+                    canonical::timestamp(&tip.timestamp)
+                ),
+            ));
+        }
+
+        if new_block.height != tip.height + 1 {
+            debug!(
+                "Block claims height {}, expected {}",
+                new_block.height,
+                tip.height + 1
+            );
+            return Ok(error_reply(
+                ErrorCode::WrongHeight,
+                format!("The height of the block should be {}", tip.height + 1),
+            ));
+        }
+    }
+
     // we get the proposers fingerprint by finding the transaction (id) then extracting the source
     let internal_user_fingerprint =
         if let Some(coinbase) = pending_transactions.get(&new_block.transaction_list[0]) {
@@ -433,12 +880,10 @@ pub async fn propose_block(
                 new_block.transaction_list[0]
             );
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "First transaction in the block is not found in the system".to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            return Ok(error_reply(
+                ErrorCode::UnknownTransaction,
+                "First transaction in the block is not found in the system".to_owned(),
+            ));
         };
 
     let users_store = db.users.upgradable_read();
@@ -452,30 +897,32 @@ pub async fn propose_block(
             new_block.transaction_list[0]
         );
 
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: "User with that public key signature is not found in the database".to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::UnknownUser,
+            "User with that public key signature is not found in the database".to_owned(),
+        ));
     };
 
     let proposer_public_key = &internal_user.public_key;
 
     // JWT Check
-    let token_payload = match authorize_proposer(&token, proposer_public_key) {
-        Ok(data) => data,
-        Err(below) => {
-            debug!("Something went wrong with the JWT {:?}", below);
+    let token_payload =
+        match authorize_proposer(&token, proposer_public_key, internal_user_fingerprint, &db) {
+            Ok(data) => data,
+            Err((code, below)) => {
+                debug!("Something went wrong with the JWT {:?}", below);
 
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: below,
-            });
+                return Ok(error_reply(code, below));
+            }
+        };
 
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
-        }
-    };
+    if internal_user.is_frozen {
+        debug!("Frozen user {} proposed a block", internal_user_fingerprint);
+        return Ok(error_reply(
+            ErrorCode::UserFrozen,
+            "Your account is frozen, you cannot propose blocks".to_owned(),
+        ));
+    }
 
     // Block hash check
     if token_payload.claims.tha != new_block.hash {
@@ -483,34 +930,27 @@ pub async fn propose_block(
             "The Hash of the block {:?} did not match the hash given in jwt {:?}",
             new_block.hash, token_payload.claims.tha
         );
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: "The hash of the block did not match the hash given in JWT tha field"
-                .to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::JwtHashMismatch,
+            "The hash of the block did not match the hash given in JWT tha field".to_owned(),
+        ));
     }
 
     if !has_unique_elements(&new_block.transaction_list) {
         debug!("Block contains duplicate transactions!");
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: "Block cannot contain duplicate transactions".to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::DuplicateTransaction,
+            "Block cannot contain duplicate transactions".to_owned(),
+        ));
     }
 
     // Are transactions in the block valid?
     for transaction_hash in &new_block.transaction_list {
         if !pending_transactions.contains_key(transaction_hash) {
-            let res_json = warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "Block contains an unknown transaction".to_owned(),
-            });
-
-            return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+            return Ok(error_reply(
+                ErrorCode::UnknownTransaction,
+                "Block contains an unknown transaction".to_owned(),
+            ));
         }
     }
 
@@ -519,180 +959,276 @@ pub async fn propose_block(
         transaction_list: new_block.transaction_list.clone(),
         nonce: new_block.nonce,
         timestamp: new_block.timestamp,
+        previous_hash: new_block.previous_hash.clone(),
+        height: new_block.height,
     };
 
-    let naked_block_flat = serde_json::to_vec(&naked_block).unwrap();
+    let naked_block_flat = canonical::naked_block(&naked_block);
 
-    let hashvalue = Blake2s::digest(&naked_block_flat);
-    let hash_string = format!("{:x}", hashvalue);
+    let hashvalue = difficulty::digest(config.pow_hash, &naked_block_flat);
+    let hash_string = hashvalue.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    });
 
     // Does the hash claimed in block match with the actual hash?
     if hash_string != new_block.hash {
         debug!("request was not telling the truth, hash values do not match");
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: "Given hash value does not match the actual block hash".to_owned(),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+        return Ok(error_reply(
+            ErrorCode::HashMismatch,
+            "Given hash value does not match the actual block hash".to_owned(),
+        ));
     }
 
-    // Are the n leftmost characters zero?
-    let hash_correct = hash_string
-        .chars()
-        .take(db.config.hash_zeros.into())
-        .all(|x| x == '0');
-
-    if !hash_correct {
-        debug!(
-            "The hash does not have {} leftmost zero characters",
-            db.config.hash_zeros
-        );
-        let res_json = warp::reply::json(&UserFeedback {
-            res: ResponseType::Error,
-            message: format!(
-                "Given block hash does not start with {} zero hexadecimal characters",
-                db.config.hash_zeros
-            ),
-        });
-
-        return Ok(warp::reply::with_status(res_json, StatusCode::BAD_REQUEST));
+    // Is the hash at most the target?
+    let target = difficulty::next_target(&db.blockchain.read(), &config);
+
+    if !difficulty::meets_target(&hashvalue, &target) {
+        let target = difficulty::format_target(&target);
+        debug!("The hash is greater than the target {}", target);
+        return Ok(error_reply(
+            ErrorCode::InsufficientWork,
+            if config.retarget.is_some() {
+                format!("Given block hash is greater than the current target {target}")
+            } else if let Some(bits) = config.hash_zero_bits {
+                format!("Given block hash does not start with {bits} zero bits")
+            } else {
+                format!(
+                    "Given block hash does not start with {} zero hexadecimal characters",
+                    config.hash_zeros
+                )
+            },
+        ));
     }
 
+    // Play out the block on the current balances before changing anything
+    let new_balances =
+        match play_out_block(&new_block, &pending_transactions, &users_store, &config) {
+            Ok(balances) => balances,
+            Err((code, below)) => {
+                debug!("Block cannot be played out: {}", below);
+                return Ok(error_reply(code, below));
+            }
+        };
+
     // All clear, block accepted!
-    warn!("[{}] ACCEPTED BLOCK {:?}", db.config.name, new_block);
+    warn!("[{}] ACCEPTED BLOCK {:?}", config.name, new_block);
 
-    // Scope the read guards
+    // Scope the write guards, the block is on the chain before they are released
     {
         let mut pending_transactions = RwLockUpgradableReadGuard::upgrade(pending_transactions);
         let mut users_store = RwLockUpgradableReadGuard::upgrade(users_store);
 
-        // Reward the block proposer
-        // All unwrap calls here are guaranteed to succeed because they are already checked above
-        // See: internal_user_fingerprint, internal_user
-        let coinbase = pending_transactions
-            .get(&new_block.transaction_list[0])
-            .unwrap();
-        let mut coinbase_user = users_store.get_mut(&coinbase.source).unwrap();
-        coinbase_user.balance += db.config.block_reward;
-        debug!(
-            "{} block reward went to {} for mining the block",
-            db.config.block_reward, coinbase_user.user_id
-        );
+        // The block reward, transfers and traffic rewards were already played out
+        for (fingerprint, balance) in new_balances {
+            if let Some(user) = users_store.get_mut(&fingerprint) {
+                user.balance = balance;
+            }
+        }
 
         let mut holding: HashMap<String, Transaction> = HashMap::new();
 
-        // Play out the transactions
+        // The bodies and the rewards are kept in the block, whatever the proposer sent is discarded
+        new_block.transactions.clear();
+        new_block.reward = Some(config.block_reward);
+        new_block.traffic_reward = Some(config.tx_traffic_reward);
+        new_block.target = Some(difficulty::format_target(&target));
+
+        // Move the transactions from the pool to the block
         for fingerprint in &new_block.transaction_list {
             if let Some(transaction) = pending_transactions.remove(fingerprint) {
                 let source = &transaction.source;
                 let target = &transaction.target;
 
-                if let Some(from) = users_store.get_mut(source) {
-                    from.balance -= transaction.amount - db.config.tx_traffic_reward;
-                }
-
-                if let Some(to) = users_store.get_mut(target) {
-                    to.balance += transaction.amount;
-                }
-
                 // if the receiver is a bot, they will reciprocate
                 if users_store.get(target).unwrap().is_bot {
-                    let transaction_id = calculate_transaction_id(target, source);
-                    holding.insert(
-                        transaction_id,
-                        Transaction {
-                            source: target.clone(),
-                            target: source.clone(),
-                            amount: transaction.amount,
-                            timestamp: Utc::now().naive_local(),
-                        },
-                    );
+                    let reciprocation = Transaction {
+                        source: target.clone(),
+                        target: source.clone(),
+                        amount: transaction.amount,
+                        timestamp: Utc::now().naive_utc(),
+                        fee: None,
+                    };
+                    holding.insert(calculate_transaction_id(&reciprocation), reciprocation);
                 }
+
+                new_block.transactions.push(transaction);
             }
         }
 
+        // The block is on disk before its transactions leave the pending folder and the balances
+        // are written. After a crash in between, the mined transactions are dropped when the pool
+        // is restored and the balances can be rebuilt with --replay --repair
+        let block_json = serde_json::to_string(&new_block).unwrap();
+        write_block(&db, &new_block.hash, &block_json);
+        for fingerprint in &new_block.transaction_list {
+            remove_pending(&db, fingerprint);
+        }
+
         for (fp, tx) in &holding {
+            // Bots do not pay gas fees, a full pool keeps the transactions that did
+            let rank = (0, Utc::now().naive_utc());
+            if !has_room_for(&db, &config, &pending_transactions, None, rank) {
+                warn!(
+                    "[{}] The pool is full, dropped the reciprocation {:?}",
+                    config.name, tx
+                );
+                continue;
+            }
+            record_transaction(&db, fp, tx, 0);
+            make_room(&db, &config, &mut pending_transactions, &mut users_store);
+            write_pending(&db, fp, tx);
             pending_transactions.insert(fp.clone(), tx.clone());
         }
 
         // just update everyone's .guy file
-        for (fp, user) in users_store.iter() {
-            if !user.is_bot {
-                let user_at_rest_json = serde_json::to_string(&UserAtRest {
-                    fingerprint: fp.clone(),
-                    user: User {
-                        user_id: user.user_id.clone(),
-                        public_key: user.public_key.clone(),
-                        balance: user.balance,
-                        is_bot: false,
-                    },
-                })
-                .unwrap();
-                write_guy_file(&db.config.name, &user.user_id, &user_at_rest_json);
+        write_user_snapshots(&db, &users_store);
+
+        // The next proposal can only see the new tip
+        // The tip was checked under the same lock, a fork here is a bug
+        db.blockchain.write().push(new_block).unwrap_or_else(|e| {
+            panic!(
+                "[{}] Accepted a block that forks the chain: {}",
+                config.name, e
+            )
+        });
+    }
+
+    Ok(success_reply(
+        "Block accepted, coinbase reward awarded".to_owned(),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Balances of the users in `block` after it is accepted
+///
+/// The proposer (source of the first transaction) gets the block reward, then the transactions
+/// are played out in order. Every source pays the amount and gets the traffic reward back.
+///
+/// Balances were only checked when the transactions entered the pool, a source with several
+/// pending transactions can spend the same coins more than once. Each source has to afford the
+/// amount with what is left after the transactions before it in the block.
+/// Fails without changing anything if a source cannot afford its transaction or a balance would
+/// overflow.
+fn play_out_block(
+    block: &Block,
+    pending_transactions: &HashMap<Id, Transaction>,
+    users: &HashMap<Fingerprint, User>,
+    config: &Config,
+) -> Result<HashMap<Fingerprint, Amount>, (ErrorCode, String)> {
+    let transactions: Vec<(&Id, &Transaction)> = block
+        .transaction_list
+        .iter()
+        .filter_map(|id| pending_transactions.get(id).map(|tx| (id, tx)))
+        .collect();
+
+    let mut balances: HashMap<Fingerprint, Amount> = HashMap::new();
+    for (_, transaction) in &transactions {
+        for fingerprint in &[&transaction.source, &transaction.target] {
+            if let Some(user) = users.get(*fingerprint) {
+                balances.insert((*fingerprint).clone(), user.balance);
             }
         }
     }
 
-    let block_json = serde_json::to_string(&new_block).unwrap();
+    // Reward the block proposer, the fees of the transactions were escrowed when they were proposed
+    if let Some((_, coinbase)) = transactions.first() {
+        let fees = transactions
+            .iter()
+            .map(|(_, transaction)| transaction.fee.unwrap_or(0))
+            .try_fold(0, Amount::checked_add);
+        if let Some(balance) = balances.get_mut(&coinbase.source) {
+            *balance = fees
+                .and_then(|fees| balance.checked_add(config.block_reward)?.checked_add(fees))
+                .ok_or_else(|| {
+                    (
+                        ErrorCode::BalanceOverflow,
+                        format!(
+                            "The block reward and fees overflow the balance of {}",
+                            coinbase.source
+                        ),
+                    )
+                })?;
+        }
+    }
 
-    write_block(
-        &db.config.name,
-        new_block.timestamp.timestamp(),
-        &block_json,
-    );
+    for (id, transaction) in &transactions {
+        if let Some(balance) = balances.get_mut(&transaction.source) {
+            let remaining = balance.checked_sub(transaction.amount).ok_or_else(|| {
+                debug!(
+                    "Double spend in block, {} has {} left for transaction {} of {}",
+                    transaction.source, balance, id, transaction.amount
+                );
+                (
+                    ErrorCode::DoubleSpend,
+                    format!(
+                        "Transaction {} spends {} but its source {} only has {} left after the \
+                         transactions before it in the block, the block contains a double spend",
+                        id, transaction.amount, transaction.source, balance
+                    ),
+                )
+            })?;
+
+            *balance = remaining
+                .checked_add(config.tx_traffic_reward)
+                .ok_or_else(|| {
+                    (
+                        ErrorCode::BalanceOverflow,
+                        format!("Transaction {id} overflows the balance of its source"),
+                    )
+                })?;
+        }
 
-    {
-        let mut blockchain = db.blockchain.write();
-        *blockchain = new_block;
+        if let Some(balance) = balances.get_mut(&transaction.target) {
+            *balance = balance.checked_add(transaction.amount).ok_or_else(|| {
+                (
+                    ErrorCode::BalanceOverflow,
+                    format!("Transaction {id} overflows the balance of its target"),
+                )
+            })?;
+        }
     }
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&UserFeedback {
-            res: ResponseType::Success,
-            message: "Block accepted, coinbase reward awarded".to_owned(),
-        }),
-        StatusCode::CREATED,
-    ))
+    Ok(balances)
 }
 
+/// Take the gas fee of `new_transaction` and record the payment under `transaction_id`
+///
+/// The `tha` of the JWT is checked against [`canonical::replacement`] if the transaction replaces
+/// the pending transaction `replaces`
 async fn deduct_gas_fee(
     new_transaction: &Transaction,
+    transaction_id: &str,
+    replaces: Option<&Id>,
     token: &str,
+    gas_fee: Amount,
     db: Db,
 ) -> Option<warp::reply::WithStatus<warp::reply::Json>> {
     let mut users_store = db.users.write();
 
     // Is this transaction from an authorized source?
-    let mut internal_user: &mut User = if let Some(existing_user) =
-        users_store.get_mut(&new_transaction.source)
-    {
-        existing_user
-    } else {
-        debug!(
-            "User with public key signature {:?} is not found in the database",
-            new_transaction.source
-        );
+    let mut internal_user: &mut User =
+        if let Some(existing_user) = users_store.get_mut(&new_transaction.source) {
+            existing_user
+        } else {
+            debug!(
+                "User with public key signature {:?} is not found in the database",
+                new_transaction.source
+            );
 
-        return Some(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "User with the given public key signature is not authorized".to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
-    };
+            return Some(error_reply(
+                ErrorCode::UnknownUser,
+                "User with the given public key signature is not authorized".to_owned(),
+            ));
+        };
 
     // This check is early on because bots don't have public keys, avoiding undefined behaviour
     if internal_user.is_bot {
         debug!("Someone tried to send as a bot");
 
-        return Some(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "Don't send transactions on behalf of bots".to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
+        return Some(error_reply(
+            ErrorCode::BotTransaction,
+            "Don't send transactions on behalf of bots".to_owned(),
         ));
     }
 
@@ -700,57 +1236,75 @@ async fn deduct_gas_fee(
     // *this* point
     let proposer_public_key = &internal_user.public_key;
 
-    let token_payload = match authorize_proposer(token, proposer_public_key) {
-        Ok(data) => data,
-        Err(below) => {
-            debug!("JWT Error: {:?}", below);
-            return Some(warp::reply::with_status(
-                warp::reply::json(&UserFeedback {
-                    res: ResponseType::Error,
-                    message: below,
-                }),
-                StatusCode::BAD_REQUEST,
-            ));
-        }
-    };
+    let token_payload =
+        match authorize_proposer(token, proposer_public_key, &new_transaction.source, &db) {
+            Ok(data) => data,
+            Err((code, below)) => {
+                debug!("JWT Error: {:?}", below);
+                return Some(error_reply(code, below));
+            }
+        };
 
-    // this transaction was already checked for correctness at custom_filters we can panic here if
-    // it has been changed since
-    let serd_tx = serde_json::to_string(&new_transaction).unwrap();
+    let serd_tx = match replaces {
+        Some(id) => canonical::replacement(id, new_transaction),
+        None => canonical::transaction(new_transaction),
+    };
 
     debug!("Taking the hash of {}", serd_tx);
 
     let hashed_transaction = Md5::digest(serd_tx.as_bytes());
 
     if token_payload.claims.tha != format!("{:x}", hashed_transaction) {
-        return Some(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "The hash of the transaction did not match the hash given in JWT"
-                    .to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
+        return Some(error_reply(
+            ErrorCode::JwtHashMismatch,
+            "The hash of the transaction did not match the hash given in JWT".to_owned(),
+        ));
+    }
+
+    // Frozen users do not pay the gas fee, their transactions never get in
+    if internal_user.is_frozen {
+        debug!(
+            "Frozen user {} proposed a transaction",
+            new_transaction.source
+        );
+        return Some(error_reply(
+            ErrorCode::UserFrozen,
+            "Your account is frozen, you cannot propose transactions".to_owned(),
+        ));
+    }
+
+    // Ids are derived from the contents, the same transaction cannot be proposed twice.
+    // Checked while holding the users lock so that two copies cannot both get through
+    if db.transaction_records.read().contains_key(transaction_id) {
+        debug!("Transaction {} was proposed before", transaction_id);
+        return Some(error_reply(
+            ErrorCode::DuplicateTransactionId,
+            format!(
+                "Transaction {transaction_id} was proposed before, change its timestamp to send \
+                it again"
+            ),
         ));
     }
 
     // At this point we have authorized the user
     // Deduct gas fee to process the transaction further
-    if internal_user.balance < db.config.tx_gas_fee {
+    if let Some(balance) = internal_user.balance.checked_sub(gas_fee) {
+        internal_user.balance = balance;
+        // The transaction outlives a restart in the pool, so does the fee it paid
+        write_user(&db, &new_transaction.source, internal_user);
+        // Record the payment, the balances are replayed from these records
+        record_transaction(&db, transaction_id, new_transaction, gas_fee);
+    } else {
         debug!(
             "User does not have enough balance ({}) to pay for the gas fee",
             internal_user.balance
         );
-        return Some(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "You cannot afford the gas fee for this transaction".to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
+        return Some(error_reply(
+            ErrorCode::InsufficientGas,
+            "You cannot afford the gas fee for this transaction".to_owned(),
         ));
     }
 
-    internal_user.balance -= db.config.tx_gas_fee;
-
     None
 }
 
@@ -763,19 +1317,180 @@ async fn deduct_gas_fee(
 /// * `token` - An Authorization header value such as `Bearer aaa.bbb.ccc`
 /// * `db` - Global [`Db`] instance
 ///
-#[allow(clippy::too_many_lines)] // temporary, should be refactored
 pub async fn propose_transaction(
     new_transaction: Transaction,
     token: String,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(accept_transaction(new_transaction, token, db, None).await)
+}
+
+/// PUT /transaction/{id}
+///
+/// Replaces the pending transaction `id` with `new_transaction`, replace-by-fee.
+/// The new transaction should have the same source, it is authorized and pays the gas fee like a
+/// new proposal. The `tha` of the JWT is the MD5 of [`canonical::replacement`], `id` followed by
+/// the canonical transaction, so that the signature names the transaction it replaces.
+/// The replaced transaction does not get its gas fee back
+pub async fn replace_transaction(
+    id: Id,
+    new_transaction: Transaction,
+    token: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(accept_transaction(new_transaction, token, db, Some(id)).await)
+}
+
+/// DELETE /transaction/{id}
+///
+/// Withdraws the pending transaction `id`, the JWT is signed by the source of the transaction and
+/// its `tha` is `id`. The gas fee is not given back
+pub async fn cancel_transaction(
+    id: Id,
+    token: String,
+    db: Db,
+) -> Result<impl warp::Reply, Infallible> {
+    let config = db.config();
+
+    let Some(transaction) = db.pending_transactions.read().get(&id).cloned() else {
+        return Ok(error_reply(
+            ErrorCode::TransactionNotFound,
+            format!("Transaction {id} is not pending"),
+        ));
+    };
+
+    let Some(public_key) = db
+        .users
+        .read()
+        .get(&transaction.source)
+        .map(|user| user.public_key.clone())
+    else {
+        return Ok(error_reply(
+            ErrorCode::UnknownUser,
+            "The source of the transaction is not found in the system".to_owned(),
+        ));
+    };
+
+    let token_payload = match authorize_proposer(&token, &public_key, &transaction.source, &db) {
+        Ok(data) => data,
+        Err((code, below)) => {
+            debug!("JWT Error: {:?}", below);
+            return Ok(error_reply(code, below));
+        }
+    };
+
+    if token_payload.claims.tha != id {
+        return Ok(error_reply(
+            ErrorCode::JwtHashMismatch,
+            "The tha field of the JWT should be the id of the transaction".to_owned(),
+        ));
+    }
+
+    let mut pending_transactions = db.pending_transactions.write();
+    if !pending_transactions.contains_key(&id) {
+        return Ok(error_reply(
+            ErrorCode::TransactionNotFound,
+            format!("Transaction {id} is not pending anymore"),
+        ));
+    }
+    let mut users_store = db.users.write();
+    evict_pending(
+        &db,
+        &config,
+        &id,
+        EvictionReason::Cancelled,
+        &mut pending_transactions,
+        &mut users_store,
+    );
+
+    Ok(success_reply(
+        format!("Transaction {id} is cancelled"),
+        StatusCode::OK,
+    ))
+}
+
+/// A new transaction, in place of the pending transaction `replaces` if given
+#[allow(clippy::too_many_lines)] // temporary, should be refactored
+async fn accept_transaction(
+    new_transaction: Transaction,
+    token: String,
+    db: Db,
+    replaces: Option<Id>,
+) -> reply::WithStatus<reply::Json> {
+    // The whole proposal is judged by the configuration at the time it arrived
+    let config = db.config();
     warn!(
         "[{}] New transaction proposal: {:?}",
-        db.config.name, &new_transaction
+        config.name, &new_transaction
+    );
+
+    // A stale transaction should not stand in the way of a new one
+    expire_pending(&db, &config);
+
+    // Only the source of a transaction can replace it, checked again by the JWT
+    if let Some(id) = &replaces {
+        match db.pending_transactions.read().get(id) {
+            None => {
+                return error_reply(
+                    ErrorCode::TransactionNotFound,
+                    format!("Transaction {id} is not pending"),
+                );
+            }
+            Some(pending) if pending.source != new_transaction.source => {
+                return error_reply(
+                    ErrorCode::NotTransactionSource,
+                    "A transaction can only be replaced by one from the same source".to_owned(),
+                );
+            }
+            // Otherwise a miner could never count on the fee of a transaction it picked
+            Some(pending)
+                if config.fee_market.is_some()
+                    && new_transaction.fee.unwrap_or(0) <= pending.fee.unwrap_or(0) =>
+            {
+                return error_reply(
+                    ErrorCode::ReplacementFeeTooLow,
+                    format!(
+                        "The replacement should offer a higher fee than the {} of transaction {}",
+                        pending.fee.unwrap_or(0),
+                        id
+                    ),
+                );
+            }
+            Some(_) => {}
+        }
+    }
+
+    // A full pool only takes transactions that outrank the ones they push out, checked before the
+    // gas fee is taken
+    let rank = (
+        config
+            .tx_gas_fee
+            .saturating_add(new_transaction.fee.unwrap_or(0)),
+        Utc::now().naive_utc(),
     );
+    if !has_room_for(
+        &db,
+        &config,
+        &db.pending_transactions.read(),
+        replaces.as_ref(),
+        rank,
+    ) {
+        return mempool_full();
+    }
+
+    let transaction_id = calculate_transaction_id(&new_transaction);
 
-    if let Some(error) = deduct_gas_fee(&new_transaction, &token, db.clone()).await {
-        return Ok(error);
+    if let Some(error) = deduct_gas_fee(
+        &new_transaction,
+        &transaction_id,
+        replaces.as_ref(),
+        &token,
+        config.tx_gas_fee,
+        db.clone(),
+    )
+    .await
+    {
+        return error;
     }
 
     // Gas fee exists to discourage dumb bots
@@ -795,13 +1510,10 @@ pub async fn propose_transaction(
             new_transaction.source
         );
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "User with the given public key signature is not authorized".to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
+        return error_reply(
+            ErrorCode::UnknownUser,
+            "User with the given public key signature is not authorized".to_owned(),
+        );
     };
 
     // is the target of the transaction in the system?
@@ -811,16 +1523,13 @@ pub async fn propose_transaction(
             new_transaction.target
         );
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
-                    "Target of the transaction {} is not found in the system",
-                    new_transaction.target
-                ),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
+        return error_reply(
+            ErrorCode::UnknownTarget,
+            format!(
+                "Target of the transaction {} is not found in the system",
+                new_transaction.target
+            ),
+        );
     }
 
     // Is this a duplicate transaction
@@ -831,21 +1540,20 @@ pub async fn propose_transaction(
             new_transaction.source, new_transaction.target,
         );
 
-        for tx in transactions.values() {
+        for (id, tx) in transactions.iter() {
+            if replaces.as_ref() == Some(id) {
+                continue;
+            }
             if tx.source == new_transaction.source && tx.target == new_transaction.target {
                 debug!(
                     "There is already a transaction from {} to {}",
                     new_transaction.source, new_transaction.target
                 );
-                return Ok(warp::reply::with_status(
-                    warp::reply::json(&UserFeedback {
-                        res: ResponseType::Error,
-                        message:
-                            "This user already has another pending transaction with this recipient"
-                                .to_owned(),
-                    }),
-                    StatusCode::BAD_REQUEST,
-                ));
+                return error_reply(
+                    ErrorCode::DuplicatePendingTx,
+                    "This user already has another pending transaction with this recipient"
+                        .to_owned(),
+                );
             }
         }
     }
@@ -853,77 +1561,369 @@ pub async fn propose_transaction(
     if new_transaction.source == new_transaction.target {
         debug!("transaction source and target are the same",);
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "transaction to yourself, you had to try didn't you? :)".to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
+        return error_reply(
+            ErrorCode::SelfTransaction,
+            "transaction to yourself, you had to try didn't you? :)".to_owned(),
+        );
     }
 
     // Is transaction amount within bounds
-    let tx_upper_limit = db.config.tx_upper_limit;
-    let tx_lower_limit = db.config.tx_lower_limit;
+    let tx_upper_limit = config.tx_upper_limit;
+    let tx_lower_limit = config.tx_lower_limit;
     if new_transaction.amount > tx_upper_limit || new_transaction.amount < tx_lower_limit {
         debug!(
             "Transaction amount is not between {} and {}, was {}",
             tx_lower_limit, tx_upper_limit, new_transaction.amount
         );
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: format!(
-                    "Transaction amount should be between {} and {}",
-                    tx_lower_limit, tx_upper_limit
-                ),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
+        return error_reply(
+            ErrorCode::AmountOutOfBounds,
+            format!(
+                "Transaction amount should be between {} and {}",
+                tx_lower_limit, tx_upper_limit
+            ),
+        );
+    }
+
+    // Is the fee within bounds, transactions cannot carry one without a fee market
+    match (&config.fee_market, new_transaction.fee) {
+        (Some(fee_market), fee) => {
+            let fee = fee.unwrap_or(0);
+            if fee < fee_market.min_fee || fee > fee_market.max_fee {
+                debug!(
+                    "Transaction fee is not between {} and {}, was {}",
+                    fee_market.min_fee, fee_market.max_fee, fee
+                );
+                return error_reply(
+                    ErrorCode::FeeOutOfBounds,
+                    format!(
+                        "Transaction fee should be between {} and {}",
+                        fee_market.min_fee, fee_market.max_fee
+                    ),
+                );
+            }
+        }
+        (None, Some(_)) => {
+            return error_reply(
+                ErrorCode::FeeOutOfBounds,
+                "This network does not take transaction fees, leave the fee out".to_owned(),
+            );
+        }
+        (None, None) => {}
+    }
+
+    if let Some(clock_skew) = &config.clock_skew {
+        if let Err(message) = check_clock_skew(&new_transaction.timestamp, clock_skew) {
+            debug!(
+                "Transaction timestamp {} rejected",
+                new_transaction.timestamp
+            );
+            return error_reply(ErrorCode::TimestampOutOfBounds, message);
+        }
     }
 
-    // check if user can afford the transaction
-    if internal_user.balance < new_transaction.amount {
+    // check if user can afford the transaction along with its fee
+    let fee = new_transaction.fee.unwrap_or(0);
+    if internal_user.balance < new_transaction.amount.saturating_add(fee) {
         debug!(
-            "User does not have enough balance ({}) for this TX {}",
-            internal_user.balance, new_transaction.amount
+            "User does not have enough balance ({}) for this TX {} with a fee of {}",
+            internal_user.balance, new_transaction.amount, fee
+        );
+        return error_reply(
+            ErrorCode::InsufficientBalance,
+            "User does not have enough balance in their account for this transaction".to_owned(),
         );
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&UserFeedback {
-                res: ResponseType::Error,
-                message: "User does not have enough balance in their account for this transaction"
-                    .to_owned(),
-            }),
-            StatusCode::BAD_REQUEST,
-        ));
     }
 
     warn!(
         "[{}] ACCEPTED TRANSACTION {:?}",
-        db.config.name, new_transaction
+        config.name, new_transaction
     );
 
+    drop(users_store);
     let mut transactions = db.pending_transactions.write();
+    let mut users_store = db.users.write();
+
+    if let Some(id) = &replaces {
+        // The replaced transaction could have been mined or evicted since
+        if !transactions.contains_key(id) {
+            return error_reply(
+                ErrorCode::TransactionNotFound,
+                format!("Transaction {id} is not pending anymore"),
+            );
+        }
+    }
+
+    // The pool could have filled up since, the gas fee is paid but the fee is not escrowed yet
+    let rank = pool_rank(
+        db.transaction_records.read().get(&transaction_id),
+        &new_transaction,
+    );
+    if !has_room_for(&db, &config, &transactions, replaces.as_ref(), rank) {
+        return mempool_full();
+    }
+
+    // The fee is held back until the transaction is mined or evicted, the balance could have
+    // changed since it was checked
+    if fee > 0 {
+        let Some(user) = users_store.get_mut(&new_transaction.source) else {
+            return error_reply(
+                ErrorCode::UnknownUser,
+                "User with the given public key signature is not authorized".to_owned(),
+            );
+        };
+        let Some(balance) = user.balance.checked_sub(fee) else {
+            return error_reply(
+                ErrorCode::InsufficientBalance,
+                "User does not have enough balance in their account for the fee".to_owned(),
+            );
+        };
+        user.balance = balance;
+        write_user(&db, &new_transaction.source, user);
 
-    let transaction_id = calculate_transaction_id(&new_transaction.source, &new_transaction.target);
-    transactions.insert(transaction_id, new_transaction);
+        if let Some(record) = db.transaction_records.write().get_mut(&transaction_id) {
+            record.escrowed_fee = Some(fee);
+            write_record(&db, &transaction_id, record);
+        }
+    }
 
-    Ok(warp::reply::with_status(
-        warp::reply::json(&UserFeedback {
+    if let Some(id) = &replaces {
+        evict_pending(
+            &db,
+            &config,
+            id,
+            EvictionReason::Replaced,
+            &mut transactions,
+            &mut users_store,
+        );
+    }
+    make_room(&db, &config, &mut transactions, &mut users_store);
+    write_pending(&db, &transaction_id, &new_transaction);
+    transactions.insert(transaction_id.clone(), new_transaction);
+
+    reply::with_status(
+        reply::json(&UserFeedback {
             res: ResponseType::Success,
+            code: None,
             message: "Transaction accepted".to_owned(),
+            id: Some(transaction_id),
         }),
         StatusCode::CREATED,
-    ))
+    )
 }
 
 /// GET /block
 /// Returns the last block's JSON
 /// Cannot fail
 pub async fn list_blocks(db: Db) -> Result<impl warp::Reply, Infallible> {
-    let block = db.blockchain.read();
+    let blockchain = db.blockchain.read();
+
+    Ok(reply::with_status(
+        reply::json(blockchain.tip()),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /block/{hash}
+/// Returns the JSON of the block with the given hash
+pub async fn get_block_by_hash(hash: String, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let blockchain = db.blockchain.read();
+
+    Ok(block_or_not_found(
+        blockchain.get_by_hash(&hash),
+        format!("There is no block with hash {hash}"),
+    ))
+}
+
+/// GET /block/height/{n}
+/// Returns the JSON of the block at the given height, the genesis block is at height 0
+pub async fn get_block_by_height(height: usize, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let blockchain = db.blockchain.read();
+
+    Ok(block_or_not_found(
+        blockchain.get_by_height(height),
+        format!("There is no block at height {height}"),
+    ))
+}
+
+/// GET /blocks
+/// Returns a page of blocks in ascending height, starting with the genesis block
+///
+/// Takes the optional `page` and `per_page` query parameters, `per_page` is capped at [`MAX_BLOCKS_PER_PAGE`]
+pub async fn list_chain(query: BlockPage, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let blockchain = db.blockchain.read();
+
+    let per_page = query.per_page.clamp(1, MAX_BLOCKS_PER_PAGE);
+    let blocks = blockchain.range(query.page.saturating_mul(per_page), per_page);
 
-    Ok(reply::with_status(reply::json(&*block), StatusCode::OK))
+    Ok(reply::with_status(
+        reply::json(&ChainPage {
+            total: blockchain.block_count(),
+            page: query.page,
+            per_page,
+            blocks,
+        }),
+        StatusCode::OK,
+    ))
+}
+
+fn block_or_not_found(block: Option<&Block>, message: String) -> reply::WithStatus<reply::Json> {
+    if let Some(block) = block {
+        return reply::with_status(reply::json(block), StatusCode::OK);
+    }
+
+    debug!("{}", message);
+    error_reply(ErrorCode::BlockNotFound, message)
+}
+
+/// GET /user/{fingerprint}/history
+/// Returns the JSON of every confirmed and pending transaction involving the user,
+/// along with the block rewards, traffic rewards, gas fees and transaction fees that changed their
+/// balance
+///
+/// Rewards are reported with the amounts in the current configuration
+pub async fn user_history(fingerprint: String, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let config = db.config();
+    expire_pending(&db, &config);
+    let mut history = UserHistory {
+        fingerprint,
+        ..UserHistory::default()
+    };
+
+    // The locks are taken one at a time, propose_block holds several of them at once
+    if let Some(user) = db.users.read().get(&history.fingerprint) {
+        history.balance = user.balance;
+    } else {
+        debug!("History of unknown user {} requested", history.fingerprint);
+        return Ok(error_reply(
+            ErrorCode::UserNotFound,
+            format!("User {} is not found in the system", history.fingerprint),
+        ));
+    }
+
+    let records = db.transaction_records.read().clone();
+
+    {
+        let blockchain = db.blockchain.read();
+        // Skip the genesis block
+        for block in blockchain.range(1, blockchain.block_count()) {
+            for (index, id) in block.transaction_list.iter().enumerate() {
+                // Blocks that were accepted before they carried their transactions fall back to
+                // the records
+                let Some(transaction) = block
+                    .transactions
+                    .get(index)
+                    .or_else(|| records.get(id).map(|record| &record.transaction))
+                else {
+                    continue;
+                };
+
+                if transaction.source != history.fingerprint
+                    && transaction.target != history.fingerprint
+                {
+                    continue;
+                }
+
+                if transaction.source == history.fingerprint {
+                    if index == 0 {
+                        history.block_rewards.push(BlockReward {
+                            height: block.height,
+                            block_hash: block.hash.clone(),
+                            amount: block.reward.unwrap_or(config.block_reward),
+                            fees: block_fees(block, &records),
+                        });
+                    }
+
+                    history.traffic_rewards.push(TrafficReward {
+                        id: id.clone(),
+                        height: block.height,
+                        amount: block.traffic_reward.unwrap_or(config.tx_traffic_reward),
+                    });
+                }
+
+                history.confirmed.push(ConfirmedTransaction {
+                    id: id.clone(),
+                    height: block.height,
+                    block_hash: block.hash.clone(),
+                    transaction: transaction.clone(),
+                });
+            }
+        }
+    }
+
+    {
+        let pending_transactions = db.pending_transactions.read();
+        for (id, transaction) in pending_transactions.iter() {
+            if transaction.source == history.fingerprint
+                || transaction.target == history.fingerprint
+            {
+                history.pending.push(PendingTransaction {
+                    id: id.clone(),
+                    transaction: transaction.clone(),
+                });
+            }
+        }
+    }
+
+    add_record_history(&mut history, &records);
+    history
+        .pending
+        .sort_by_key(|pending| pending.transaction.timestamp);
+
+    Ok(reply::with_status(reply::json(&history), StatusCode::OK))
+}
+
+/// Add the gas fees, transaction fees and evictions of the transactions the user sent
+fn add_record_history(history: &mut UserHistory, records: &HashMap<Id, TransactionRecord>) {
+    for (id, record) in records {
+        if record.transaction.source != history.fingerprint {
+            continue;
+        }
+        if record.gas_fee > 0 {
+            history.gas_fees.push(GasFee {
+                id: id.clone(),
+                amount: record.gas_fee,
+            });
+        }
+        if let (Some(fee), None) = (record.escrowed_fee, &record.eviction) {
+            history.transaction_fees.push(GasFee {
+                id: id.clone(),
+                amount: fee,
+            });
+        }
+        if let Some(eviction) = &record.eviction {
+            history.evicted.push(EvictedTransaction {
+                id: id.clone(),
+                transaction: record.transaction.clone(),
+                eviction: eviction.clone(),
+            });
+        }
+    }
+
+    history
+        .gas_fees
+        .sort_by_key(|gas_fee| records[&gas_fee.id].transaction.timestamp);
+    history
+        .transaction_fees
+        .sort_by_key(|fee| records[&fee.id].transaction.timestamp);
+    history
+        .evicted
+        .sort_by_key(|evicted| evicted.eviction.timestamp);
+}
+
+/// The sum of the fees of the transactions in `block`, paid to its proposer
+fn block_fees(block: &Block, records: &HashMap<Id, TransactionRecord>) -> Amount {
+    block
+        .transaction_list
+        .iter()
+        .enumerate()
+        .filter_map(|(index, id)| {
+            block
+                .transactions
+                .get(index)
+                .or_else(|| records.get(id).map(|record| &record.transaction))
+        })
+        .map(|transaction| transaction.fee.unwrap_or(0))
+        .fold(0, Amount::saturating_add)
 }
 
 /// GET /user
@@ -945,15 +1945,184 @@ pub async fn user_list_handler(db: Db) -> Result<impl warp::Reply, warp::Rejecti
     Ok(warp::reply::html(res))
 }
 
+/// POST /admin
+///
+/// Carries out an [`AdminAction`] of the network operators,
+/// every authorized action is appended to the audit log of the network whether it succeeds or not
+/// # Arguments
+/// * `action` - Valid JSON of an [`AdminAction`]
+/// * `token` - An Authorization header value such as `Bearer aaa.bbb.ccc`, signed with the
+///   private key of the network
+/// * `db` - Global [`Db`] instance
+pub async fn admin_action(
+    action: AdminAction,
+    token: String,
+    db: Db,
+) -> Result<impl warp::Reply, Infallible> {
+    let config = db.config();
+    warn!("[{}] New admin action: {:?}", config.name, action);
+
+    if let Err((code, message)) = authorize_admin(&token, &action, &db) {
+        warn!(
+            "[{}] Unauthorized admin action {:?}: {}",
+            config.name, action, message
+        );
+        return Ok(error_reply(code, message));
+    }
+
+    let mut previous_balance = None;
+    let outcome = match &action {
+        AdminAction::SetBalance {
+            fingerprint,
+            balance,
+        } => {
+            let mut users_store = db.users.write();
+            if let Some(user) = users_store.get_mut(fingerprint) {
+                previous_balance = Some(user.balance);
+                let message = format!(
+                    "Balance of {fingerprint} is set to {balance}, it was {}",
+                    user.balance
+                );
+                user.balance = *balance;
+                write_user(&db, fingerprint, user);
+                Ok(message)
+            } else {
+                Err(unknown_user(fingerprint))
+            }
+        }
+        AdminAction::Freeze { fingerprint } => set_frozen(&db, fingerprint, true),
+        AdminAction::Unfreeze { fingerprint } => set_frozen(&db, fingerprint, false),
+        AdminAction::RemoveTransaction { id } => {
+            let mut pending_transactions = db.pending_transactions.write();
+            if pending_transactions.contains_key(id) {
+                evict_pending(
+                    &db,
+                    &config,
+                    id,
+                    EvictionReason::Removed,
+                    &mut pending_transactions,
+                    &mut db.users.write(),
+                );
+                Ok(format!(
+                    "Transaction {id} is removed from the pending transactions"
+                ))
+            } else {
+                Err((
+                    ErrorCode::TransactionNotFound,
+                    format!("Transaction {id} is not pending"),
+                ))
+            }
+        }
+        AdminAction::ReloadPreapprovedUsers => db
+            .reload_preapproved_users()
+            .map(|count| format!("Reloaded the preapproved users, {count} users can register"))
+            .map_err(|e| (ErrorCode::ReloadFailed, e)),
+        AdminAction::Snapshot => {
+            let count = write_user_snapshots(&db, &db.users.read());
+            Ok(format!("Wrote the .guy files of {count} users"))
+        }
+        AdminAction::ReloadConfig => match db.reload_config() {
+            Ok(changes) if changes.is_empty() => {
+                Ok("Reloaded the config, nothing changed".to_owned())
+            }
+            Ok(changes) => Ok(format!("Reloaded the config, {}", changes.join(", "))),
+            Err(e) => Err((ErrorCode::ReloadFailed, e)),
+        },
+    };
+
+    let (Ok(message) | Err((_, message))) = &outcome;
+    warn!("[{}] Admin action {:?}: {}", config.name, action, message);
+
+    append_audit_entry(
+        &db,
+        &AuditEntry {
+            timestamp: Utc::now().naive_utc(),
+            succeeded: outcome.is_ok(),
+            message: message.clone(),
+            action,
+            previous_balance,
+        },
+    );
+
+    Ok(match outcome {
+        Ok(message) => success_reply(message, StatusCode::OK),
+        Err((code, message)) => error_reply(code, message),
+    })
+}
+
+fn set_frozen(db: &Db, fingerprint: &str, frozen: bool) -> Result<String, (ErrorCode, String)> {
+    let mut users_store = db.users.write();
+    let Some(user) = users_store.get_mut(fingerprint) else {
+        return Err(unknown_user(fingerprint));
+    };
+
+    user.is_frozen = frozen;
+    write_user(db, fingerprint, user);
+
+    Ok(if frozen {
+        format!("{fingerprint} is frozen")
+    } else {
+        format!("{fingerprint} is no longer frozen")
+    })
+}
+
+fn unknown_user(fingerprint: &str) -> (ErrorCode, String) {
+    (
+        ErrorCode::UserNotFound,
+        format!("User {fingerprint} is not found in the system"),
+    )
+}
+
+/// Admins sign their tokens with the private key of the network,
+/// `tha` is the MD5 hash of the [`AdminAction`]
+fn authorize_admin(
+    jwt_token: &str,
+    action: &AdminAction,
+    db: &Db,
+) -> Result<(), (ErrorCode, String)> {
+    let modulus = base64::encode_config(db.private_key.n().to_bytes_be(), base64::URL_SAFE_NO_PAD);
+    let exponent = base64::encode_config(db.private_key.e().to_bytes_be(), base64::URL_SAFE_NO_PAD);
+    let decoding_key = DecodingKey::from_rsa_components(&modulus, &exponent);
+
+    let token_payload = verify_token(jwt_token, &decoding_key)?;
+
+    // Admin tokens move balances around, they are always single use and short lived
+    let max_lifetime = db
+        .config()
+        .jwt_max_lifetime
+        .map_or(ADMIN_MAX_LIFETIME, |lifetime| {
+            lifetime.min(ADMIN_MAX_LIFETIME)
+        });
+    check_token_reuse(ADMIN, &token_payload.claims, Some(max_lifetime), true, db)?;
+
+    let serd_action = serde_json::to_string(action).unwrap();
+    let hashed_action = Md5::digest(serd_action.as_bytes());
+
+    if token_payload.claims.tha != format!("{hashed_action:x}") {
+        return Err((
+            ErrorCode::JwtHashMismatch,
+            "The hash of the action did not match the hash given in JWT".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
 /// Handles the JWT Authorization
 ///
 /// *[`jwt_token`]: The raw JWT token, "Bearer aaa.bbb.ccc"
 /// *[`user_pem`]: User Public Key, "BEGIN RSA"
+/// *[`fingerprint`]: Fingerprint of the user, the owner of `user_pem`
 /// NOT async, might look into it if this becomes a bottleneck
-fn authorize_proposer(jwt_token: &str, user_pem: &str) -> Result<TokenData<Claims>, String> {
-    // Throw away the "Bearer " part
-    let raw_jwt = jwt_token.trim_start_matches(BEARER).to_owned();
-
+///
+/// Enforces the token lifetime and replay protection settings of the network, see
+/// [`check_token_reuse`]
+fn authorize_proposer(
+    jwt_token: &str,
+    user_pem: &str,
+    fingerprint: &str,
+    db: &Db,
+) -> Result<TokenData<Claims>, (ErrorCode, String)> {
     // Extract a jsonwebtoken compatible decoding_key from user's public key
     let decoding_key = match DecodingKey::from_rsa_pem(user_pem.as_bytes()) {
         Ok(key) => key,
@@ -962,56 +2131,439 @@ fn authorize_proposer(jwt_token: &str, user_pem: &str) -> Result<TokenData<Claim
                 "given RSA key {} is invalid, we should crash and burn here {:?}",
                 user_pem, j
             );
-            return Err(String::from("This User's RSA key is invalid"));
+            return Err((
+                ErrorCode::InvalidRsaKey,
+                String::from("This User's RSA key is invalid"),
+            ));
         }
     };
 
+    let token_payload = verify_token(jwt_token, &decoding_key)?;
+
+    let config = db.config();
+    check_token_reuse(
+        fingerprint,
+        &token_payload.claims,
+        config.jwt_max_lifetime,
+        config.jwt_replay_protection,
+        db,
+    )?;
+
+    Ok(token_payload)
+}
+
+/// Check the signature and the expiration of a `Bearer` JWT
+fn verify_token(
+    jwt_token: &str,
+    decoding_key: &DecodingKey,
+) -> Result<TokenData<Claims>, (ErrorCode, String)> {
+    // Throw away the "Bearer " part
+    let raw_jwt = jwt_token.trim_start_matches(BEARER).to_owned();
+
     // Extract the payload inside the JWT
-    let token_payload =
-        match decode::<Claims>(&raw_jwt, &decoding_key, &Validation::new(Algorithm::RS256)) {
-            Ok(decoded) => decoded,
-            Err(err) => match *err.kind() {
-                ErrorKind::InvalidToken => {
-                    debug!("raw_jwt={:?} was malformed err={:?}", raw_jwt, err);
-                    return Err(String::from("Invalid Token"));
-                }
-                ErrorKind::InvalidRsaKey => {
-                    debug!("The RSA key does not have a valid format, {:?}", err);
-                    return Err(String::from("The RSA key does not have a valid format"));
-                }
-                ErrorKind::ExpiredSignature => {
-                    debug!("this token has expired {:?}", err);
-                    return Err(String::from("This token has expired"));
-                }
-                _ => {
-                    warn!(
-                        "AN UNSPECIFIED ERROR from token: {}\nerr: {:?} key was {}",
-                        raw_jwt, err, user_pem
-                    );
-                    return Err(format!("JWT Error: {}", err));
-                }
-            },
+    match decode::<Claims>(&raw_jwt, decoding_key, &Validation::new(Algorithm::RS256)) {
+        Ok(decoded) => Ok(decoded),
+        Err(err) => match *err.kind() {
+            ErrorKind::InvalidToken => {
+                debug!("raw_jwt={:?} was malformed err={:?}", raw_jwt, err);
+                Err((ErrorCode::InvalidToken, String::from("Invalid Token")))
+            }
+            ErrorKind::InvalidRsaKey => {
+                debug!("The RSA key does not have a valid format, {:?}", err);
+                Err((
+                    ErrorCode::InvalidRsaKey,
+                    String::from("The RSA key does not have a valid format"),
+                ))
+            }
+            ErrorKind::ExpiredSignature => {
+                debug!("this token has expired {:?}", err);
+                Err((
+                    ErrorCode::JwtExpired,
+                    String::from("This token has expired"),
+                ))
+            }
+            _ => {
+                warn!(
+                    "AN UNSPECIFIED ERROR from token: {}\nerr: {:?}",
+                    raw_jwt, err
+                );
+                Err((ErrorCode::JwtError, format!("JWT Error: {err}")))
+            }
+        },
+    }
+}
+
+/// Rejects tokens that live longer than `max_lifetime` seconds, and tokens that were used before
+/// if `replay_protection` is on
+///
+/// The `jti` of every token is remembered until the token expires
+fn check_token_reuse(
+    fingerprint: &str,
+    claims: &Claims,
+    max_lifetime: Option<u64>,
+    replay_protection: bool,
+    db: &Db,
+) -> Result<(), (ErrorCode, String)> {
+    let now = usize::try_from(Utc::now().timestamp()).unwrap_or_default();
+
+    if let Some(max_lifetime) = max_lifetime {
+        if claims.iat > now + IAT_LEEWAY {
+            debug!(
+                "Token was issued in the future, iat {} now {}",
+                claims.iat, now
+            );
+            return Err((
+                ErrorCode::JwtIssuedInFuture,
+                String::from("This token was issued in the future"),
+            ));
+        }
+
+        let lifetime = u64::try_from(claims.exp.saturating_sub(claims.iat)).unwrap_or(u64::MAX);
+        if lifetime > max_lifetime {
+            debug!("Token lifetime {} exceeds {}", lifetime, max_lifetime);
+            return Err((
+                ErrorCode::JwtLifetimeTooLong,
+                format!("Tokens cannot be valid for more than {max_lifetime} seconds (exp - iat)"),
+            ));
+        }
+    }
+
+    if replay_protection {
+        let Some(jti) = &claims.jti else {
+            return Err((
+                ErrorCode::JwtMissingJti,
+                String::from("This token needs a unique 'jti' claim"),
+            ));
         };
 
-    Ok(token_payload)
+        let mut seen_tokens = db.seen_tokens.write();
+
+        // Expired tokens are rejected anyway, no need to remember them
+        seen_tokens.retain(|_, exp| *exp >= now);
+
+        if seen_tokens
+            .insert((fingerprint.to_owned(), jti.clone()), claims.exp)
+            .is_some()
+        {
+            warn!("{} tried to reuse the token {}", fingerprint, jti);
+            return Err((
+                ErrorCode::JwtReused,
+                String::from("This token has been used before"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_guy_file(db: &Db, guy: &MetuId, content: &str) {
+    fs::write(db.users_dir().join(format!("{guy}.guy")), content).unwrap();
+}
+
+/// Write the `.guy` file of a user, bots are not written to disk
+fn write_user(db: &Db, fingerprint: &str, user: &User) {
+    if user.is_bot {
+        return;
+    }
+
+    let user_at_rest_json = serde_json::to_string(&UserAtRest {
+        fingerprint: fingerprint.to_owned(),
+        user: User {
+            user_id: user.user_id.clone(),
+            public_key: user.public_key.clone(),
+            balance: user.balance,
+            is_bot: false,
+            is_frozen: user.is_frozen,
+            register_bonus: user.register_bonus,
+        },
+    })
+    .unwrap();
+    write_guy_file(db, &user.user_id, &user_at_rest_json);
 }
 
-fn write_guy_file(network_name: &str, guy: &MetuId, content: &str) {
-    fs::write(format!("users/{}/{}.guy", network_name, guy), content).unwrap();
+/// Write the `.guy` file of every user, returns how many were written
+fn write_user_snapshots(db: &Db, users: &HashMap<Fingerprint, User>) -> usize {
+    for (fingerprint, user) in users {
+        write_user(db, fingerprint, user);
+    }
+    users.values().filter(|user| !user.is_bot).count()
 }
 
-fn write_block(network_name: &str, timestamp: i64, block: &str) {
+fn append_audit_entry(db: &Db, entry: &AuditEntry) {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(db.audit_file())
+        .unwrap();
+    writeln!(file, "{}", serde_json::to_string(entry).unwrap()).unwrap();
+}
+
+/// Blocks are named by their hash, which no other block can have
+fn write_block(db: &Db, hash: &str, block: &str) {
+    fs::write(db.blocks_dir().join(format!("{hash}.block")), block).unwrap();
+}
+
+/// Is `timestamp` within `clock_skew` of the clock of the server?
+fn check_clock_skew(timestamp: &NaiveDateTime, clock_skew: &ClockSkewConfig) -> Result<(), String> {
+    let now = Utc::now().naive_utc();
+    let ahead = (*timestamp - now).num_seconds();
+
+    if ahead > 0 && ahead.unsigned_abs() > clock_skew.max_future {
+        return Err(format!(
+            "The timestamp is {ahead} seconds in the future, it can be at most {} seconds ahead \
+            of the server: {}",
+            clock_skew.max_future,
+            now.format("%Y-%m-%dT%H:%M:%S")
+        ));
+    }
+    if ahead < 0 && ahead.unsigned_abs() > clock_skew.max_past {
+        return Err(format!(
+            "The timestamp is {} seconds in the past, it can be at most {} seconds behind the \
+            server: {}",
+            ahead.unsigned_abs(),
+            clock_skew.max_past,
+            now.format("%Y-%m-%dT%H:%M:%S")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Keep a [`TransactionRecord`] in memory and on disk
+fn record_transaction(db: &Db, transaction_id: &str, transaction: &Transaction, gas_fee: Amount) {
+    let record = TransactionRecord {
+        transaction: transaction.clone(),
+        gas_fee,
+        received: Some(Utc::now().naive_utc()),
+        eviction: None,
+        escrowed_fee: None,
+    };
+
+    write_record(db, transaction_id, &record);
+
+    db.transaction_records
+        .write()
+        .insert(transaction_id.to_owned(), record);
+}
+
+fn write_record(db: &Db, transaction_id: &str, record: &TransactionRecord) {
+    fs::write(
+        db.transactions_dir().join(format!("{transaction_id}.tx")),
+        serde_json::to_string(record).unwrap(),
+    )
+    .unwrap();
+}
+
+/// Keep a pending transaction on disk until it leaves the pool, see [`remove_pending`]
+fn write_pending(db: &Db, transaction_id: &str, transaction: &Transaction) {
     fs::write(
-        format!("blocks/{}/{}.block", network_name, timestamp),
-        block,
+        db.pending_dir().join(format!("{transaction_id}.tx")),
+        serde_json::to_string(transaction).unwrap(),
     )
     .unwrap();
 }
 
-fn calculate_transaction_id(source: &str, target: &str) -> String {
-    let long_fingerprint = format!("{}{}{}", source, target, Utc::now());
-    let id = format!("{:x}", Sha256::digest(long_fingerprint.as_bytes()));
-    id
+fn remove_pending(db: &Db, transaction_id: &str) {
+    if let Err(e) = fs::remove_file(db.pending_dir().join(format!("{transaction_id}.tx"))) {
+        warn!(
+            "Cannot remove the pending transaction {}: {}",
+            transaction_id, e
+        );
+    }
+}
+
+/// Evict the pending transactions that waited longer than the time to live of the pool
+fn expire_pending(db: &Db, config: &Config) {
+    let Some(ttl) = config.mempool.as_ref().and_then(|mempool| mempool.ttl) else {
+        return;
+    };
+    let now = Utc::now().naive_utc();
+
+    let mut pending_transactions = db.pending_transactions.write();
+    let expired: Vec<Id> = {
+        let records = db.transaction_records.read();
+        pending_transactions
+            .iter()
+            .filter(|(id, transaction)| {
+                let received = records
+                    .get(*id)
+                    .and_then(|record| record.received)
+                    .unwrap_or(transaction.timestamp);
+                u64::try_from((now - received).num_seconds()).is_ok_and(|age| age > ttl)
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    };
+    if expired.is_empty() {
+        return;
+    }
+
+    let mut users_store = db.users.write();
+    for id in expired {
+        evict_pending(
+            db,
+            config,
+            &id,
+            EvictionReason::Expired,
+            &mut pending_transactions,
+            &mut users_store,
+        );
+    }
+}
+
+/// Where a pending transaction stands when the pool is full, the lowest goes first:
+/// the ones that paid the least gas and fee and the oldest among them
+fn pool_rank(
+    record: Option<&TransactionRecord>,
+    transaction: &Transaction,
+) -> (Amount, NaiveDateTime) {
+    (
+        record
+            .map_or(0, |record| record.gas_fee)
+            .saturating_add(transaction.fee.unwrap_or(0)),
+        record
+            .and_then(|record| record.received)
+            .unwrap_or(transaction.timestamp),
+    )
+}
+
+/// Whether a transaction of `rank` gets into the pool, it has to outrank every transaction
+/// [`make_room`] would evict for it. `replaced` leaves the pool anyway and is not counted
+fn has_room_for(
+    db: &Db,
+    config: &Config,
+    pending_transactions: &HashMap<Id, Transaction>,
+    replaced: Option<&Id>,
+    rank: (Amount, NaiveDateTime),
+) -> bool {
+    let Some(max_size) = config.mempool.as_ref().and_then(|mempool| mempool.max_size) else {
+        return true;
+    };
+
+    let records = db.transaction_records.read();
+    let mut ranks: Vec<(Amount, NaiveDateTime)> = pending_transactions
+        .iter()
+        .filter(|(id, _)| Some(*id) != replaced)
+        .map(|(id, transaction)| pool_rank(records.get(id), transaction))
+        .collect();
+    if ranks.len() < max_size {
+        return true;
+    }
+
+    // The pool can be over its size after a reload, more than one would have to go
+    let evicted = ranks.len() + 1 - max_size;
+    ranks.sort_unstable();
+    ranks[evicted - 1] < rank
+}
+
+/// Evict pending transactions until there is room for one more, lowest [`pool_rank`] first
+///
+/// Check [`has_room_for`] beforehand, the newcomer could rank below the ones it pushes out
+fn make_room(
+    db: &Db,
+    config: &Config,
+    pending_transactions: &mut HashMap<Id, Transaction>,
+    users_store: &mut HashMap<Fingerprint, User>,
+) {
+    let Some(max_size) = config.mempool.as_ref().and_then(|mempool| mempool.max_size) else {
+        return;
+    };
+
+    while pending_transactions.len() >= max_size {
+        let cheapest = {
+            let records = db.transaction_records.read();
+            pending_transactions
+                .iter()
+                .min_by_key(|(id, transaction)| pool_rank(records.get(*id), transaction))
+                .map(|(id, _)| id.clone())
+        };
+        let Some(id) = cheapest else {
+            break;
+        };
+        evict_pending(
+            db,
+            config,
+            &id,
+            EvictionReason::PoolFull,
+            pending_transactions,
+            users_store,
+        );
+    }
+}
+
+/// A full pool turned the transaction away
+fn mempool_full() -> reply::WithStatus<reply::Json> {
+    error_reply(
+        ErrorCode::MempoolFull,
+        "The pool is full of transactions that paid more, offer a higher fee or try again later"
+            .to_owned(),
+    )
+}
+
+/// Remove a transaction from the pool without mining it, the eviction is kept in its record
+fn evict_pending(
+    db: &Db,
+    config: &Config,
+    transaction_id: &str,
+    reason: EvictionReason,
+    pending_transactions: &mut HashMap<Id, Transaction>,
+    users_store: &mut HashMap<Fingerprint, User>,
+) {
+    let Some(transaction) = pending_transactions.remove(transaction_id) else {
+        return;
+    };
+    remove_pending(db, transaction_id);
+
+    let mut records = db.transaction_records.write();
+    let Some(record) = records.get_mut(transaction_id) else {
+        warn!("Evicted transaction {} has no record", transaction_id);
+        return;
+    };
+
+    // Only the transactions the pool dropped on its own can get their gas fee back
+    let mut refund = 0;
+    let dropped_by_pool = matches!(reason, EvictionReason::Expired | EvictionReason::PoolFull);
+    if dropped_by_pool
+        && config
+            .mempool
+            .as_ref()
+            .is_some_and(|mempool| mempool.refund_gas)
+    {
+        if let Some(user) = users_store.get_mut(&transaction.source) {
+            refund = record.gas_fee;
+            user.balance = user.balance.saturating_add(refund);
+            write_user(db, &transaction.source, user);
+        }
+    }
+
+    // The escrowed fee was never paid to anyone, it always goes back
+    let escrowed_fee = record.escrowed_fee.unwrap_or(0);
+    if escrowed_fee > 0 {
+        if let Some(user) = users_store.get_mut(&transaction.source) {
+            user.balance = user.balance.saturating_add(escrowed_fee);
+            write_user(db, &transaction.source, user);
+        }
+    }
+
+    record.eviction = Some(Eviction {
+        timestamp: Utc::now().naive_utc(),
+        reason,
+        refund,
+    });
+    write_record(db, transaction_id, record);
+
+    warn!(
+        "[{}] EVICTED TRANSACTION {} ({:?}), refunded {} and returned a fee of {}",
+        config.name, transaction_id, reason, refund, escrowed_fee
+    );
+}
+
+/// The SHA-256 of the [`canonical::transaction`] encoding, clients can compute it themselves
+fn calculate_transaction_id(transaction: &Transaction) -> Id {
+    format!(
+        "{:x}",
+        Sha256::digest(canonical::transaction(transaction).as_bytes())
+    )
 }
 
 #[derive(Template)]
@@ -1022,7 +2574,7 @@ struct UserTemplate<'a> {
 
 struct DisplayUsers {
     fingerprint: String,
-    balance: u16,
+    balance: Amount,
     is_bot: bool,
 }
 
@@ -1034,3 +2586,138 @@ where
     let mut uniq = HashSet::new();
     iter.into_iter().all(move |x| uniq.insert(x))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(balance: Amount) -> User {
+        User {
+            user_id: MetuId::new("e000000".to_owned(), "not_used".to_owned()),
+            public_key: "not_used".to_owned(),
+            balance,
+            is_bot: false,
+            is_frozen: false,
+            register_bonus: None,
+        }
+    }
+
+    fn transfer(source: &str, target: &str, amount: Amount, fee: Option<Amount>) -> Transaction {
+        Transaction {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            amount,
+            timestamp: Utc::now().naive_utc(),
+            fee,
+        }
+    }
+
+    /// A block of `transactions` in order, with the pool they are taken from
+    fn block_of(transactions: Vec<Transaction>) -> (Block, HashMap<Id, Transaction>) {
+        let mut block = Block::default();
+        block.transaction_list.clear();
+        let mut pending = HashMap::new();
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            let id = format!("tx{index}");
+            block.transaction_list.push(id.clone());
+            pending.insert(id, transaction);
+        }
+        (block, pending)
+    }
+
+    fn config() -> Config {
+        Config {
+            block_reward: 10,
+            tx_traffic_reward: 1,
+            ..Config::default()
+        }
+    }
+
+    fn users(balances: &[(&str, Amount)]) -> HashMap<Fingerprint, User> {
+        balances
+            .iter()
+            .map(|(fingerprint, balance)| ((*fingerprint).to_owned(), user(*balance)))
+            .collect()
+    }
+
+    #[test]
+    fn play_out_block_credits_the_proposer() {
+        let users = users(&[("alice", 5), ("bob", 0), ("carol", 0)]);
+        let (block, pending) = block_of(vec![
+            transfer("alice", "bob", 2, Some(3)),
+            transfer("bob", "carol", 1, Some(4)),
+        ]);
+
+        let balances = play_out_block(&block, &pending, &users, &config()).unwrap();
+
+        // alice: 5 + reward 10 + fees 7 - 2 + traffic 1
+        assert_eq!(balances["alice"], 21);
+        // bob: 0 + 2 - 1 + traffic 1
+        assert_eq!(balances["bob"], 2);
+        assert_eq!(balances["carol"], 1);
+    }
+
+    #[test]
+    fn play_out_block_rejects_a_double_spend() {
+        let users = users(&[("alice", 10), ("bob", 3), ("carol", 0), ("dave", 0)]);
+        // bob can afford either of these but not both
+        let (block, pending) = block_of(vec![
+            transfer("alice", "carol", 1, None),
+            transfer("bob", "carol", 3, None),
+            transfer("bob", "dave", 3, None),
+        ]);
+
+        let result = play_out_block(&block, &pending, &users, &config());
+
+        assert!(matches!(result, Err((ErrorCode::DoubleSpend, _))));
+    }
+
+    #[test]
+    fn play_out_block_rejects_an_overflow() {
+        let users = users(&[("alice", 10), ("bob", Amount::MAX)]);
+        let (block, pending) = block_of(vec![transfer("alice", "bob", 1, None)]);
+
+        let result = play_out_block(&block, &pending, &users, &config());
+
+        assert!(matches!(result, Err((ErrorCode::BalanceOverflow, _))));
+    }
+
+    #[test]
+    fn play_out_block_rejects_an_overflowing_reward() {
+        let users = users(&[("alice", Amount::MAX - 5), ("bob", 0)]);
+        let (block, pending) = block_of(vec![transfer("alice", "bob", 1, None)]);
+
+        let result = play_out_block(&block, &pending, &users, &config());
+
+        assert!(matches!(result, Err((ErrorCode::BalanceOverflow, _))));
+    }
+
+    #[test]
+    fn play_out_block_changes_nothing_when_it_fails() {
+        let users = users(&[("alice", 10), ("bob", 3), ("carol", 0)]);
+        let (block, pending) = block_of(vec![
+            transfer("alice", "carol", 1, Some(2)),
+            transfer("bob", "carol", 3, None),
+            transfer("bob", "alice", 3, None),
+        ]);
+        let users_before: Vec<(Fingerprint, Amount)> = {
+            let mut balances: Vec<_> = users
+                .iter()
+                .map(|(fingerprint, user)| (fingerprint.clone(), user.balance))
+                .collect();
+            balances.sort();
+            balances
+        };
+        let pending_before = pending.clone();
+
+        assert!(play_out_block(&block, &pending, &users, &config()).is_err());
+
+        let mut users_after: Vec<_> = users
+            .iter()
+            .map(|(fingerprint, user)| (fingerprint.clone(), user.balance))
+            .collect();
+        users_after.sort();
+        assert_eq!(users_after, users_before);
+        assert_eq!(pending, pending_before);
+    }
+}
@@ -9,30 +9,109 @@
 //! - Encrypts their JSON wrapped `Public Key` and `Student ID` using Gradecoin's Public Key
 //! - Their public key is now in our Db under [`block::User::public_key`] and can be used to sign their JWT's during requests
 //!
+//! ## /user/{fingerprint}/history
+//! - fetch everything that changed the balance of a user - GET request
+//!     - confirmed and pending transactions the user is a part of
+//!     - block rewards with the fees of their blocks, traffic rewards, gas fees and transaction
+//!       fees
+//!
 //! ## /transaction
 //! - offer a [`block::Transaction`] - POST request
 //!     - The request should have `Authorization`
 //!     - The request header should be signed by the Public Key of the `by` field in the transaction
+//!     - The `tha` claim of the JWT is the MD5 of the [`canonical::transaction`] encoding
+//!     - The timestamp should be within [`config::Config::clock_skew`] of the clock of the server,
+//!       in UTC
+//!     - With [`config::Config::fee_market`], the transaction should offer a
+//!       [`block::Transaction::fee`] within its bounds. The fee is held back from the source until
+//!       the transaction is mined and paid to the proposer of the block, or returned if it is
+//!       evicted. Without it, transactions cannot have a fee
+//!     - The response has the id of the transaction, the SHA-256 of its
+//!       [`canonical::transaction`] encoding. The same transaction cannot be proposed twice
+//! - replace a pending transaction with a new one from the same source - PUT request to
+//!   `/transaction/{id}`
+//!     - The same rules as a new transaction, the replaced transaction keeps its gas fee
+//!     - With [`config::Config::fee_market`], the new transaction should offer a higher fee
+//! - cancel a pending transaction - DELETE request to `/transaction/{id}`
+//!     - The request should have `Authorization` signed by the source of the transaction, the
+//!       `tha` claim is the id of the transaction
 //! - fetch the list of `Transaction`s - GET request
+//!     - With [`config::Config::mempool`], transactions that wait too long or do not fit in the
+//!       pool are evicted, see [`block::Eviction`]
+//! - fetch a transaction and whether it is pending, confirmed in a block, evicted or rejected -
+//!   GET request to `/transaction/{id}`
 //!
 //! ## /block
 //! - offer a [`block::Block`] - POST request
 //!     - The request should have `Authorization`
 //!     - The [`block::Block::transaction_list`] of the block should be a subset of [`block::Db::pending_transactions`]
+//!     - The [`block::Block::previous_hash`] of the block should be the hash of the last accepted block
+//!     - The [`block::Block::height`] of the block should be one more than the last accepted block
+//!     - The [`block::Block::timestamp`] of the block should be later than the last accepted block
+//!       and within [`config::Config::clock_skew`] of the clock of the server, in UTC
+//!     - The hash of the block is taken over its [`canonical::naked_block`] encoding and should
+//!       not be greater than the current target, see [`difficulty`]
+//!     - Every source should be able to afford its transaction after the transactions before it
+//!       in the block, no double spending
+//!     - The proposer gets the block reward and the fees of the transactions in the block
 //! - fetch the last accepted [`block::Block`] - GET request
+//!     - Accepted blocks include the bodies of their transactions in [`block::Block::transactions`]
+//!
+//! ## /block/{hash} and /block/height/{n}
+//! - fetch a previously accepted [`block::Block`] by its hash or by its height - GET request
+//!     - The genesis block is at height 0
+//!
+//! ## /blocks
+//! - fetch a page of accepted [`block::Block`]s in ascending height - GET request
+//!     - `page` and `per_page` query parameters, for example `/blocks?page=2&per_page=10`
 //!
 //! `Authorization`: The request header should have Bearer JWT.Token signed with Student Public Key
 //!
 //! ## /config
 //! - Get the current [`config::Config`] as JSON - GET request
 //!
+//! ## /difficulty
+//! - Get the target the next block should meet and when it is adjusted next - GET request
+//!
+//! ## /admin
+//! - carry out a [`block::AdminAction`] - POST request
+//!     - The request should have `Authorization`, the JWT is signed with the private key of the
+//!       network instead of a student key
+//!     - Set a balance, freeze or unfreeze a user, remove a pending transaction, reload the
+//!       preapproved users, reload the configuration or write every `.guy` file
+//!     - Every authorized action is appended to `audit/<network name>.log` in the data directory
+//!
+//! # Errors
+//!
+//! Rejected requests are answered with a JSON body such as
+//! `{"res": "Error", "code": "INSUFFICIENT_BALANCE", "message": "..."}`.
+//! The `message` is meant for humans, clients should branch on the `code`.
+//! The HTTP status is derived from the `code`: `404` for lookups that found nothing,
+//! `401` for JWT problems, `403` for requests that are never allowed, `409` for requests that
+//! conflict with the current state, `500` if an admin action failed on the server side and `400`
+//! for everything else.
+//!
+//! Requests that do not even reach a handler get the same treatment: a body that is not valid JSON
+//! for the endpoint is answered with `MALFORMED_BODY` and the name of the offending field,
+//! a missing `Authorization` header with `MISSING_HEADER` and an oversized body with
+//! `PAYLOAD_TOO_LARGE`.
+//!
 //! # Configuration
 //!
 //! The default configuration file if `config.yaml`, which will run if no command line arguments are given.
 //!
 //! You can give one or more configuration files as command line arguments.
 //! This will run all of them at the same time.
-//! Make sure that the names and URL prefixes don't clash, otherwise it will lead to undefined behavior.
+//! The names and URL prefixes of the networks should not clash.
+//!
+//! Every configuration file is validated on startup, see [`config::Config::validate`].
+//! A file with problems is left out and every problem is logged.
+//! Give `--check-config` to only report the problems, the program exits with a non-zero status
+//! if there are any.
+//!
+//! ```sh
+//! $ cargo run -- --check-config config.yaml testnet.yaml
+//! ```
 //!
 //! Example:
 //! ```sh
@@ -43,42 +122,120 @@
 //! ```
 //!
 //! See [`config::Config`] struct for more information about the configurable fields.
+//!
+//! The configuration file of a running network can be edited and applied with the
+//! `reload_config` admin action, without dropping the pending transactions.
+//! Every field can change except the name, the URL prefix and the private key.
+//!
+//! The address, the port, the data directory, the private key, the static site and the log
+//! configuration are not specific to a network, they are given as options before the
+//! configuration files, see [`options`].
+//!
+//! ```sh
+//! $ cargo run -- --port 8081 --data-dir /srv/section2 config.yaml
+//! ```
+//!
+//! # Replaying the history
+//!
+//! Give `--replay` to rebuild the balances of the given networks from their blocks and
+//! transaction records instead of starting the server.
+//! Every balance that does not agree with the `.guy` snapshots is reported and the program exits
+//! with a non-zero status. Add `--repair` to overwrite the mismatching snapshots with the replayed
+//! balances. See [`replay`] for how the balances are derived.
+//!
+//! ```sh
+//! $ cargo run -- --replay config.yaml testnet.yaml
+//! ```
 #![warn(clippy::all, clippy::pedantic)]
 #![allow(clippy::unused_async)]
 
 mod block;
+mod canonical;
 mod config;
 mod custom_filters;
 mod db;
+mod difficulty;
 mod handlers;
+mod options;
+mod replay;
 mod routes;
 mod student;
 
 use crate::config::Config;
-pub use block::{Fingerprint, Id};
+pub use block::{Amount, Fingerprint, Id};
 use db::Db;
-use lazy_static::lazy_static;
-use log::error;
-use std::fs;
+use log::{error, info};
+use options::{Mode, Options};
+use std::sync::Arc;
 use warp::Filter;
 
 #[tokio::main]
 async fn main() {
-    log4rs::init_file("log.conf.yml", log4rs::config::Deserializers::default()).unwrap();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", options::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if options.mode == Mode::Help {
+        println!("{}", options::USAGE);
+        return;
+    }
+
+    log4rs::init_file(
+        &options.log_config,
+        log4rs::config::Deserializers::default(),
+    )
+    .unwrap();
 
-    let mut args: Vec<String> = std::env::args().collect();
+    let (configs, mut valid) = read_configs(&options.configs);
 
-    if args.len() == 1 {
-        // config.yaml is the default configuration file
-        args.push("config.yaml".to_string());
+    // Only the networks without their own private key decrypt with the one of Gradecoin
+    let private_key = if configs.iter().all(|config| config.private_key.is_some()) {
+        None
+    } else {
+        match db::read_private_key(&options.key) {
+            Ok(key) => Some(Arc::new(key)),
+            Err(e) => {
+                error!("Failed to load the private key of Gradecoin: {}", e);
+                if options.mode != Mode::CheckConfig {
+                    std::process::exit(1);
+                }
+                valid = false;
+                None
+            }
+        }
+    };
+
+    if options.mode == Mode::CheckConfig {
+        if valid {
+            info!("Every config file is valid");
+            return;
+        }
+        std::process::exit(1);
     }
 
-    let combined_routes = args
+    let load_network = |config| Db::new(config, &options.data_dir, private_key.clone());
+
+    if options.mode == Mode::Replay {
+        // Rebuild the balances of every network from history and exit
+        // Every network is checked, even after an inconsistent one
+        let results: Vec<bool> = configs
+            .into_iter()
+            .map(|config| replay::check_network(&load_network(config), options.repair))
+            .collect();
+
+        if results.contains(&false) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let combined_routes = configs
         .into_iter()
-        .skip(1) // Skip the program name
-        .filter_map(|filename| {
-            Config::read(&filename).map(|config| routes::network(Db::new(config)))
-        })
+        .map(|config| routes::network(load_network(config)))
         .reduce(|routes, route| routes.or(route).unify().boxed());
 
     let routes = match combined_routes {
@@ -91,16 +248,40 @@ async fn main() {
     };
 
     // gradecoin-site (zola) outputs a public/, we serve it here
-    let static_route = warp::any().and(warp::fs::dir("public"));
+    let static_route = warp::any().and(warp::fs::dir(options.static_dir.clone()));
 
-    let api = routes.or(static_route);
+    // Rejections are only answered once neither a network nor the static site took the request
+    let api = routes.or(static_route).recover(handlers::handle_rejection);
 
     // Start the server
-    let point = ([127, 0, 0, 1], 8080);
-    warp::serve(api).run(point).await;
+    warp::serve(api).run(options.socket_address()).await;
 }
 
-lazy_static! {
-    static ref PRIVATE_KEY: String =
-        fs::read_to_string("secrets/gradecoin.pem").expect("error reading 'secrets/gradecoin.pem'");
+/// Read and validate every configuration file, the ones with problems are left out
+///
+/// Every problem is logged, the second value is `false` if there were any
+fn read_configs(filenames: &[String]) -> (Vec<Config>, bool) {
+    let mut configs: Vec<Config> = Vec::new();
+    let mut valid = true;
+
+    for filename in filenames {
+        let Some(config) = Config::read(filename) else {
+            valid = false;
+            continue;
+        };
+
+        let mut problems = config.validate().err().unwrap_or_default();
+        problems.extend(config.clashes_with(&configs));
+
+        if problems.is_empty() {
+            configs.push(config);
+        } else {
+            valid = false;
+            for problem in problems {
+                error!("{}: {}", filename, problem);
+            }
+        }
+    }
+
+    (configs, valid)
 }
@@ -0,0 +1,153 @@
+//! # Command line options
+//!
+//! Everything that is not specific to a network is given on the command line,
+//! the configuration files of the networks are the positional arguments.
+//! Every option can also be given through an environment variable, the command line wins if both
+//! are present.
+//!
+//! | Option           | Environment variable     | Default                 |
+//! |------------------|--------------------------|-------------------------|
+//! | `--address`      | `GRADECOIN_ADDRESS`      | `127.0.0.1`             |
+//! | `--port`         | `GRADECOIN_PORT`         | `8080`                  |
+//! | `--data-dir`     | `GRADECOIN_DATA_DIR`     | `.`                     |
+//! | `--key`          | `GRADECOIN_KEY`          | `secrets/gradecoin.pem` |
+//! | `--static-dir`   | `GRADECOIN_STATIC_DIR`   | `public`                |
+//! | `--log-config`   | `GRADECOIN_LOG_CONFIG`   | `log.conf.yml`          |
+//!
+//! `--data-dir` is where the `blocks`, `users`, `transactions`, `pending` and `audit` folders live.
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+pub const USAGE: &str = "\
+Usage: gradecoin [OPTIONS] [CONFIG]...
+
+Serves every network in the given configuration files, config.yaml if none are given.
+
+Options:
+    --address <IP>        Address to listen on [env: GRADECOIN_ADDRESS] [default: 127.0.0.1]
+    --port <PORT>         Port to listen on [env: GRADECOIN_PORT] [default: 8080]
+    --data-dir <DIR>      Where blocks, users, transactions and audit logs are kept [env: GRADECOIN_DATA_DIR] [default: .]
+    --key <FILE>          Private key of Gradecoin in PEM format [env: GRADECOIN_KEY] [default: secrets/gradecoin.pem]
+    --static-dir <DIR>    The static site to serve [env: GRADECOIN_STATIC_DIR] [default: public]
+    --log-config <FILE>   log4rs configuration [env: GRADECOIN_LOG_CONFIG] [default: log.conf.yml]
+    --replay              Check the balances against the history instead of serving
+    --repair              With --replay, overwrite the mismatching balances
+    --check-config        Report every problem in the configuration files instead of serving
+    --help                Print this message";
+
+/// What to do with the networks
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mode {
+    Serve,
+    /// Check the balances against the history, see [`crate::replay`]
+    Replay,
+    /// Report every problem in the configuration files
+    CheckConfig,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub address: IpAddr,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub key: PathBuf,
+    pub static_dir: PathBuf,
+    pub log_config: PathBuf,
+    pub mode: Mode,
+    /// With [`Mode::Replay`], overwrite the mismatching balances
+    pub repair: bool,
+    /// Configuration files of the networks, `config.yaml` if none were given
+    pub configs: Vec<String>,
+}
+
+impl Options {
+    /// Parse the command line arguments, the program name excluded
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+            data_dir: PathBuf::from("."),
+            key: PathBuf::from("secrets/gradecoin.pem"),
+            static_dir: PathBuf::from("public"),
+            log_config: PathBuf::from("log.conf.yml"),
+            mode: Mode::Serve,
+            repair: false,
+            configs: Vec::new(),
+        };
+
+        // Environment variables first, so that the command line can override them
+        for (name, variable) in [
+            ("--address", "GRADECOIN_ADDRESS"),
+            ("--port", "GRADECOIN_PORT"),
+            ("--data-dir", "GRADECOIN_DATA_DIR"),
+            ("--key", "GRADECOIN_KEY"),
+            ("--static-dir", "GRADECOIN_STATIC_DIR"),
+            ("--log-config", "GRADECOIN_LOG_CONFIG"),
+        ] {
+            if let Ok(value) = env::var(variable) {
+                options
+                    .set(name, value)
+                    .map_err(|e| format!("{e} (from {variable})"))?;
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--replay" => options.mode = Mode::Replay,
+                "--repair" => options.repair = true,
+                "--check-config" => options.mode = Mode::CheckConfig,
+                "--help" | "-h" => options.mode = Mode::Help,
+                _ if arg.starts_with("--") => {
+                    // Both `--port 8080` and `--port=8080` are accepted
+                    let (name, value) = if let Some((name, value)) = arg.split_once('=') {
+                        (name.to_owned(), value.to_owned())
+                    } else {
+                        let value = args
+                            .next()
+                            .ok_or_else(|| format!("{arg} expects a value"))?;
+                        (arg, value)
+                    };
+                    options.set(&name, value)?;
+                }
+                _ => options.configs.push(arg),
+            }
+        }
+
+        if options.configs.is_empty() {
+            // config.yaml is the default configuration file
+            options.configs.push("config.yaml".to_owned());
+        }
+
+        Ok(options)
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        match name {
+            "--address" => {
+                self.address = value
+                    .parse()
+                    .map_err(|_| format!("{value} is not an IP address"))?;
+            }
+            "--port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| format!("{value} is not a port number"))?;
+            }
+            "--data-dir" => self.data_dir = PathBuf::from(value),
+            "--key" => self.key = PathBuf::from(value),
+            "--static-dir" => self.static_dir = PathBuf::from(value),
+            "--log-config" => self.log_config = PathBuf::from(value),
+            _ => return Err(format!("Unknown option {name}")),
+        }
+        Ok(())
+    }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
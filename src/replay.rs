@@ -0,0 +1,323 @@
+//! # Rebuilding balances from history
+//!
+//! The `.guy` files are rewritten after every accepted block, so a crash while they are being
+//! written leaves some balances behind. The balances can instead be derived from:
+//!
+//! - the bonus every registered user got, [`Config::register_bonus`] for the users who registered
+//!   before it was recorded in their `.guy` file
+//! - [`crate::config::BotConfig::starting_balance`] for every bot
+//! - the gas fee of every [`TransactionRecord`] and the fee it escrowed if it was not evicted
+//! - the block reward, the fees, the transfers and the traffic rewards of every accepted [`Block`],
+//!   using the transaction bodies stored in the block
+//! - the balances set by the admins, recorded in the audit log as [`AuditEntry`]s
+//!
+//! [`replay`] plays the history out in order, checking that every block extends the one before it,
+//! and [`Replay::mismatches`] compares the outcome against the `.guy` snapshots.
+//! Blocks accepted before blocks were linked and transactions were recorded are reported on their
+//! own, there is nothing to replay them from and nothing is repaired while there are any.
+use crate::block::{
+    AdminAction, Amount, AuditEntry, Block, Fingerprint, Id, Transaction, TransactionRecord,
+};
+use crate::config::Config;
+use crate::db::{read_audit_log, Db};
+use crate::handlers::write_guy_file;
+use crate::student::{User, UserAtRest};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// The outcome of replaying the history of a network
+///
+/// Balances are signed so that an inconsistent history shows up as a negative balance
+/// instead of an underflow
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub balances: HashMap<Fingerprint, i128>,
+    /// Transactions referenced by a block that have no record on disk
+    pub missing_transactions: Vec<(u64, Id)>,
+    /// Heights of the blocks that do not extend the block before them
+    pub broken_links: Vec<u64>,
+    /// Heights of the blocks that were accepted before blocks were linked and transactions were
+    /// recorded, they cannot be replayed and are skipped
+    pub pre_upgrade_blocks: Vec<u64>,
+}
+
+/// A user whose snapshot does not agree with the replayed history
+#[derive(Debug)]
+pub struct Mismatch {
+    pub fingerprint: Fingerprint,
+    pub snapshot: Amount,
+    pub replayed: i128,
+}
+
+impl Replay {
+    /// Users whose balance in `users` differs from the replayed balance
+    ///
+    /// Bots are left out, their balances are not snapshotted and start over with every restart
+    pub fn mismatches(&self, users: &HashMap<Fingerprint, User>) -> Vec<Mismatch> {
+        let mut mismatches: Vec<Mismatch> = users
+            .iter()
+            .filter(|(_, user)| !user.is_bot)
+            .filter_map(|(fingerprint, user)| {
+                let replayed = self.balances.get(fingerprint).copied().unwrap_or_default();
+                if replayed == i128::from(user.balance) {
+                    None
+                } else {
+                    Some(Mismatch {
+                        fingerprint: fingerprint.clone(),
+                        snapshot: user.balance,
+                        replayed,
+                    })
+                }
+            })
+            .collect();
+
+        mismatches.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        mismatches
+    }
+}
+
+/// Derive every balance of the network from its configuration, records and blocks
+pub fn replay(
+    config: &Config,
+    users: &HashMap<Fingerprint, User>,
+    blocks: &[Block],
+    records: &HashMap<Id, TransactionRecord>,
+    audit_log: &[AuditEntry],
+) -> Replay {
+    let mut result = Replay::default();
+
+    // Starting balances
+    for (fingerprint, user) in users {
+        let starting_balance = if user.is_bot {
+            config
+                .bots
+                .get(fingerprint)
+                .map(|bot| bot.starting_balance)
+                .unwrap_or_default()
+        } else {
+            user.register_bonus.unwrap_or(config.register_bonus)
+        };
+        result
+            .balances
+            .insert(fingerprint.clone(), i128::from(starting_balance));
+    }
+
+    // Gas fees are paid when the transaction is proposed, whether it is mined or not,
+    // an evicted transaction can get its fee back and always gets its escrowed fee back
+    for record in records.values() {
+        let paid = match &record.eviction {
+            Some(eviction) => i128::from(record.gas_fee) - i128::from(eviction.refund),
+            None => i128::from(record.gas_fee) + i128::from(record.escrowed_fee.unwrap_or(0)),
+        };
+        *result
+            .balances
+            .entry(record.transaction.source.clone())
+            .or_default() -= paid;
+    }
+
+    for (index, block) in blocks.iter().enumerate() {
+        // The genesis block does not move any coins
+        if index == 0 {
+            continue;
+        }
+
+        if is_pre_upgrade(block, records) {
+            result.pre_upgrade_blocks.push(block.height);
+            continue;
+        }
+
+        let previous = &blocks[index - 1];
+        if block.previous_hash != previous.hash || block.height != previous.height + 1 {
+            result.broken_links.push(block.height);
+        }
+
+        // Blocks carry the bodies of their transactions,
+        // blocks that were accepted before that fall back to the records
+        let transactions: Vec<&Transaction> = if block.transactions.is_empty() {
+            block
+                .transaction_list
+                .iter()
+                .filter_map(|id| {
+                    let record = records.get(id);
+                    if record.is_none() {
+                        result.missing_transactions.push((block.height, id.clone()));
+                    }
+                    record.map(|record| &record.transaction)
+                })
+                .collect()
+        } else {
+            block.transactions.iter().collect()
+        };
+
+        // The source of the first transaction is the proposer
+        let coinbase = if block.transactions.is_empty() {
+            block
+                .transaction_list
+                .first()
+                .and_then(|id| records.get(id))
+                .map(|record| &record.transaction)
+        } else {
+            block.transactions.first()
+        };
+
+        if let Some(coinbase) = coinbase {
+            let fees: i128 = transactions
+                .iter()
+                .map(|transaction| i128::from(transaction.fee.unwrap_or(0)))
+                .sum();
+            *result.balances.entry(coinbase.source.clone()).or_default() +=
+                i128::from(block.reward.unwrap_or(config.block_reward)) + fees;
+        }
+
+        for transaction in transactions {
+            *result
+                .balances
+                .entry(transaction.source.clone())
+                .or_default() -= i128::from(transaction.amount)
+                - i128::from(block.traffic_reward.unwrap_or(config.tx_traffic_reward));
+            *result
+                .balances
+                .entry(transaction.target.clone())
+                .or_default() += i128::from(transaction.amount);
+        }
+    }
+
+    // An admin setting a balance moves it by the difference, wherever it was in the history
+    for entry in audit_log.iter().filter(|entry| entry.succeeded) {
+        if let (
+            AdminAction::SetBalance {
+                fingerprint,
+                balance,
+            },
+            Some(previous_balance),
+        ) = (&entry.action, entry.previous_balance)
+        {
+            *result.balances.entry(fingerprint.clone()).or_default() +=
+                i128::from(*balance) - i128::from(previous_balance);
+        }
+    }
+
+    result
+}
+
+/// Blocks accepted before blocks were linked and transactions were recorded, nothing is known about
+/// their transactions, not even who proposed them
+fn is_pre_upgrade(block: &Block, records: &HashMap<Id, TransactionRecord>) -> bool {
+    block.previous_hash.is_empty()
+        && block.transactions.is_empty()
+        && !block
+            .transaction_list
+            .iter()
+            .any(|id| records.contains_key(id))
+}
+
+/// Replay the history of the network in `db` and report every mismatch
+///
+/// With `repair`, the `.guy` files of the mismatching users are rewritten with the replayed
+/// balances. Returns `true` if the snapshots agree with the history.
+pub fn check_network(db: &Db, repair: bool) -> bool {
+    let config = db.config();
+    let name = &config.name;
+
+    let records = db.transaction_records.read();
+    let blockchain = db.blockchain.read();
+    let blocks = blockchain.range(0, blockchain.block_count());
+    let users = db.users.read();
+
+    let audit_log = match read_audit_log(&db.audit_file()) {
+        Ok(audit_log) => audit_log,
+        Err(e) => {
+            warn!("[{}] Cannot read the audit log: {}", name, e);
+            return false;
+        }
+    };
+
+    let result = replay(&config, &users, blocks, &records, &audit_log);
+
+    for height in &result.broken_links {
+        warn!(
+            "[{}] Block at height {} does not extend the block before it",
+            name, height
+        );
+    }
+
+    if let (Some(first), Some(last)) = (
+        result.pre_upgrade_blocks.first(),
+        result.pre_upgrade_blocks.last(),
+    ) {
+        warn!(
+            "[{}] {} blocks between heights {} and {} predate linked blocks and transaction \
+            records, their balances cannot be replayed",
+            name,
+            result.pre_upgrade_blocks.len(),
+            first,
+            last
+        );
+    }
+
+    for (height, id) in &result.missing_transactions {
+        warn!(
+            "[{}] Block at height {} references transaction {} which has no record",
+            name, height, id
+        );
+    }
+
+    let mismatches = result.mismatches(&users);
+    for mismatch in &mismatches {
+        warn!(
+            "[{}] {} has {} in the snapshot, replayed balance is {}",
+            name, mismatch.fingerprint, mismatch.snapshot, mismatch.replayed
+        );
+    }
+
+    info!(
+        "[{}] Replayed {} blocks and {} transaction records, {} mismatches",
+        name,
+        blocks.len(),
+        records.len(),
+        mismatches.len()
+    );
+
+    // The replayed balances leave out whatever happened in the pre-upgrade blocks
+    if repair && !result.pre_upgrade_blocks.is_empty() {
+        warn!(
+            "[{}] Not repairing, the history before the upgrade is incomplete",
+            name
+        );
+    } else if repair {
+        for mismatch in &mismatches {
+            let user = &users[&mismatch.fingerprint];
+
+            let Ok(balance) = Amount::try_from(mismatch.replayed) else {
+                warn!(
+                    "[{}] Cannot repair {}, replayed balance {} is out of range",
+                    name, mismatch.fingerprint, mismatch.replayed
+                );
+                continue;
+            };
+
+            let user_at_rest_json = serde_json::to_string(&UserAtRest {
+                fingerprint: mismatch.fingerprint.clone(),
+                user: User {
+                    user_id: user.user_id.clone(),
+                    public_key: user.public_key.clone(),
+                    balance,
+                    is_bot: false,
+                    is_frozen: user.is_frozen,
+                    register_bonus: user.register_bonus,
+                },
+            })
+            .unwrap();
+            write_guy_file(db, &user.user_id, &user_at_rest_json);
+            info!(
+                "[{}] Repaired the balance of {}",
+                name, mismatch.fingerprint
+            );
+        }
+    }
+
+    mismatches.is_empty()
+        && result.missing_transactions.is_empty()
+        && result.broken_links.is_empty()
+}
@@ -8,11 +8,9 @@ use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
 
 /// Every route combined for a single network
 pub fn network(db: Db) -> BoxedFilter<(impl Reply,)> {
-    let url_prefix = db.config.url_prefix.clone();
-    info!(
-        "{} will be served at endpoint /{}",
-        db.config.name, url_prefix
-    );
+    let config = db.config();
+    let url_prefix = config.url_prefix.clone();
+    info!("{} will be served at endpoint /{}", config.name, url_prefix);
     let root = if url_prefix.is_empty() {
         // warp::path does not like empty url_prefix
         // We need to handle this case separately
@@ -22,17 +20,36 @@ pub fn network(db: Db) -> BoxedFilter<(impl Reply,)> {
     };
     root.and(
         transaction_list(db.clone())
+            .or(transaction_by_id(db.clone()))
             .or(get_config_route(db.clone()))
+            .or(get_difficulty_route(db.clone()))
             .or(get_version())
             .or(register_user(db.clone()))
             .or(auth_transaction_propose(db.clone()))
+            .or(auth_transaction_replace(db.clone()))
+            .or(auth_transaction_cancel(db.clone()))
             .or(auth_block_propose(db.clone()))
+            .or(auth_admin_action(db.clone()))
             .or(list_users(db.clone()))
+            .or(user_history(db.clone()))
+            .or(block_by_height(db.clone()))
+            .or(block_by_hash(db.clone()))
+            .or(chain_list(db.clone()))
             .or(block_list(db)),
     )
     .boxed()
 }
 
+/// GET /difficulty warp route
+pub fn get_difficulty_route(
+    db: Db,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("difficulty")
+        .and(warp::get())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::get_difficulty)
+}
+
 /// GET /config warp route
 pub fn get_config_route(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("config")
@@ -56,6 +73,14 @@ pub fn list_users(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection
         .and_then(handlers::user_list_handler)
 }
 
+/// GET /user/{fingerprint}/history warp route
+pub fn user_history(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("user" / String / "history")
+        .and(warp::get())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::user_history)
+}
+
 /// POST /register warp route
 pub fn register_user(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("register")
@@ -73,6 +98,14 @@ pub fn transaction_list(db: Db) -> impl Filter<Extract = impl Reply, Error = Rej
         .and_then(handlers::list_transactions)
 }
 
+/// GET /transaction/{id} warp route
+pub fn transaction_by_id(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("transaction" / String)
+        .and(warp::get())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::transaction_status)
+}
+
 /// GET /block warp route
 pub fn block_list(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("block")
@@ -81,6 +114,31 @@ pub fn block_list(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection
         .and_then(handlers::list_blocks)
 }
 
+/// GET /block/{hash} warp route
+pub fn block_by_hash(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("block" / String)
+        .and(warp::get())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::get_block_by_hash)
+}
+
+/// GET /block/height/{n} warp route
+pub fn block_by_height(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("block" / "height" / usize)
+        .and(warp::get())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::get_block_by_height)
+}
+
+/// GET /blocks warp route
+pub fn chain_list(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("blocks")
+        .and(warp::get())
+        .and(custom_filters::block_page_query())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::list_chain)
+}
+
 /// POST /transaction warp route
 pub fn auth_transaction_propose(
     db: Db,
@@ -93,6 +151,39 @@ pub fn auth_transaction_propose(
         .and_then(handlers::propose_transaction)
 }
 
+/// PUT /transaction/{id} warp route
+pub fn auth_transaction_replace(
+    db: Db,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("transaction" / String)
+        .and(warp::put())
+        .and(custom_filters::transaction_json_body())
+        .and(custom_filters::auth_header())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::replace_transaction)
+}
+
+/// DELETE /transaction/{id} warp route
+pub fn auth_transaction_cancel(
+    db: Db,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("transaction" / String)
+        .and(warp::delete())
+        .and(custom_filters::auth_header())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::cancel_transaction)
+}
+
+/// POST /admin warp route
+pub fn auth_admin_action(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin")
+        .and(warp::post())
+        .and(custom_filters::admin_action_json_body())
+        .and(custom_filters::auth_header())
+        .and(custom_filters::with_db(db))
+        .and_then(handlers::admin_action)
+}
+
 /// POST /block warp route
 pub fn auth_block_propose(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("block")
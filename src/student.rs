@@ -1,4 +1,4 @@
-use crate::{Fingerprint, Id};
+use crate::{Amount, Fingerprint, Id};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -13,15 +13,22 @@ pub struct UserAtRest {
 /// - [`user_id`]: Can only be one of the preapproved students (who are enlisted in the course)
 /// - [`public_key`]: A PEM format public key "---- BEGIN" and all
 /// - [`balance`]: User's current Gradecoin amount
+/// - [`is_frozen`]: Frozen by an admin, cannot propose transactions or blocks
+/// - [`register_bonus`]: What the user got for registering, the config can change afterwards.
+///   Users who registered before it was recorded get the current bonus of the network
 ///
 /// This should ideally include the fingerprint as well?
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct User {
     pub user_id: MetuId,
     pub public_key: String,
-    pub balance: u16,
+    pub balance: Amount,
     #[serde(skip, default = "bool::default")]
     pub is_bot: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_frozen: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub register_bonus: Option<Amount>,
 }
 
 impl fmt::Display for User {